@@ -3,16 +3,29 @@ local posixfiles = {}
 local arg = arg
 _G.arg = nil
 
+function posixfiles.init(root)
+  return posixfiles.forroot(root)
+end
+
+-- If metadata keys are passed to 'query', or metadata to 'apply', the
+-- shadow file holds just the contents of the file. Otherwise, its first line
+-- is a header with the file's mode.
 function posixfiles.forroot(root)
   local root = root:gsub('/*$', '/')
   return {
     exists = function(path)
       return posixfiles.osexists(root .. path)
     end,
-    query = function(path, shadowpath)
+    query = function(path, shadowpath, keys)
+      if keys then
+        return posixfiles.osquerymeta(root .. path, shadowpath, keys)
+      end
       posixfiles.osquery(root .. path, shadowpath)
     end,
-    apply = function(path, shadowpath)
+    apply = function(path, shadowpath, meta)
+      if meta then
+        return posixfiles.osapplymeta(root .. path, shadowpath, meta)
+      end
       posixfiles.osapply(root .. path, shadowpath)
     end,
   }
@@ -56,6 +69,50 @@ function posixfiles.osapply(ospath, shadowpath)
   execf("chmod %s '%s'", mode, ospath)
 end
 
+local statformats = {mode = '%a', owner = '%U', group = '%G'}
+
+function posixfiles.osquerymeta(ospath, shadowpath, keys)
+  posixfiles.stat(ospath, 'regular file')
+  execf("cat '%s' > '%s'", ospath, shadowpath)
+  local meta = {}
+  for _, key in ipairs(keys) do
+    local format = statformats[key]
+    if not format then
+      error(("unsupported metadata key %q"):format(key))
+    end
+    local h = assert(io.popen(("stat -c '%s' '%s'"):format(format, ospath), "r"))
+    meta[key] = assert(h:read'*l')
+    h:close()
+  end
+  if meta.mode then
+    meta.mode = ('%04o'):format(tonumber(meta.mode, 8))
+  end
+  return meta
+end
+
+function posixfiles.osapplymeta(ospath, shadowpath, meta)
+  if not posixfiles.osexists(shadowpath) then
+    assert(os.remove(ospath))
+    return
+  end
+  for key in pairs(meta) do
+    if not statformats[key] then
+      error(("unsupported metadata key %q"):format(key))
+    end
+  end
+  execf("cat '%s' > '%s'", shadowpath, ospath)
+  if meta.mode then
+    execf("chmod %s '%s'", meta.mode, ospath)
+  end
+  if meta.owner and meta.group then
+    execf("chown '%s:%s' '%s'", meta.owner, meta.group, ospath)
+  elseif meta.owner then
+    execf("chown '%s' '%s'", meta.owner, ospath)
+  elseif meta.group then
+    execf("chgrp '%s' '%s'", meta.group, ospath)
+  end
+end
+
 function posixfiles.header(shadowpath)
   local fh = assert(io.open(shadowpath, 'r'))
   local header = assert(fh:read'*l')
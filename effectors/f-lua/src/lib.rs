@@ -1,4 +1,5 @@
 use anyhow::{bail, Result};
+use effectors::Metadata;
 use fn_error_context::context;
 use mlua::prelude::{Lua, LuaMultiValue, LuaValue};
 use path_slash::PathExt as _;
@@ -69,7 +70,7 @@ impl Effector {
             bail!("*lua expected a table from `require({name:?}).init(...)`, got: {obj_:?}");
         };
         // store result in `_G._MANA`
-        let _ = g.set(MANA_GLOBAL, obj_)?;
+        g.set(MANA_GLOBAL, obj_)?;
         Ok(())
     }
 
@@ -112,17 +113,24 @@ impl effectors::Callee for Effector {
         self.call_method("exists", path.to_slash())
     }
 
-    fn gather(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    // Metadata keys are passed to `query` as an array, if any. The method
+    // should then return a table with the values of these keys.
+    fn gather(&mut self, path: &Path, shadow_prefix: &Path, keys: &[String]) -> Result<Metadata> {
         let shadow_path = shadow_prefix.join(path);
         // FIXME: `path` should be slash'ed on input here
         let path = path.to_slash();
-        self.call_method("query", (path, shadow_path.to_str().unwrap()))
+        let keys = (!keys.is_empty()).then_some(keys);
+        let meta: Option<Metadata> =
+            self.call_method("query", (path, shadow_path.to_str().unwrap(), keys))?;
+        Ok(meta.unwrap_or_default())
     }
 
-    fn affect(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    // Metadata is passed to `apply` as a table, if any.
+    fn affect(&mut self, path: &Path, shadow_prefix: &Path, meta: &Metadata) -> Result<()> {
         let shadow_path = shadow_prefix.join(path);
         // FIXME: `path` should be slash'ed on input here
         let path = path.to_slash();
-        self.call_method("apply", (path, shadow_path.to_str().unwrap()))
+        let meta = (!meta.is_empty()).then_some(meta.clone());
+        self.call_method("apply", (path, shadow_path.to_str().unwrap(), meta))
     }
 }
@@ -1,5 +1,6 @@
 use anyhow::{bail, Result};
 use clap::Parser;
+use effectors::Metadata;
 use fn_error_context::context;
 use remotefs::RemoteFs;
 use remotefs_ssh::{ScpFs, SshAgentIdentity, SshOpts};
@@ -9,14 +10,15 @@ use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
+#[command(group = clap::ArgGroup::new("key").required(true).multiple(false))]
 pub struct Args {
     #[arg(long)]
     host: String,
     #[arg(long)]
     user: String,
-    #[arg(long)]
+    #[arg(long, group = "key")]
     key_path: Option<PathBuf>,
-    #[arg(long)]
+    #[arg(long, group = "key")]
     key_agent: bool,
     #[arg(long)]
     base_dir: Option<PathBuf>,
@@ -78,15 +80,21 @@ impl effectors::Callee for Effector {
     }
 
     #[context("*scp gathering {path:?} to {shadow_prefix:?}")]
-    fn gather(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    fn gather(&mut self, path: &Path, shadow_prefix: &Path, keys: &[String]) -> Result<Metadata> {
+        if !keys.is_empty() {
+            bail!("*scp does not support metadata, got: {keys:?}");
+        }
         let mut r = self.client.open(&self.based(path))?;
         let mut w = File::create(shadow_prefix.join(path))?;
         std::io::copy(&mut r, &mut w)?;
-        Ok(())
+        Ok(Metadata::new())
     }
 
     #[context("*scp affecting {path:?} to {shadow_prefix:?}")]
-    fn affect(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    fn affect(&mut self, path: &Path, shadow_prefix: &Path, meta: &Metadata) -> Result<()> {
+        if !meta.is_empty() {
+            bail!("*scp does not support metadata, got: {meta:?}");
+        }
         let maybe_r = File::open(shadow_prefix.join(path));
         // Handle file-not-found scenario - remove remote file
         // TODO: merge two ifs once let-chains are stabilized
@@ -1,6 +1,7 @@
 pub mod xmlutil;
 
 use anyhow::{bail, Result};
+use effectors::Metadata;
 use itertools::Itertools;
 use log::debug;
 use path_slash::PathBufExt as _;
@@ -30,13 +31,19 @@ impl effectors::Callee for Effector {
         Ok(self.apps.contains_key(path))
     }
 
-    fn gather(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    fn gather(&mut self, path: &Path, shadow_prefix: &Path, keys: &[String]) -> Result<Metadata> {
+        if !keys.is_empty() {
+            bail!("*zeroinstall does not support metadata, got: {keys:?}");
+        }
         let s = self.apps.get(path).unwrap();
         std::fs::write(shadow_prefix.join(path), s)?;
-        Ok(())
+        Ok(Metadata::new())
     }
 
-    fn affect(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    fn affect(&mut self, path: &Path, shadow_prefix: &Path, meta: &Metadata) -> Result<()> {
+        if !meta.is_empty() {
+            bail!("*zeroinstall does not support metadata, got: {meta:?}");
+        }
         //println!("MCDBG path={path:?}, spfx={shadow_prefix:?}");
         let shadow_path = shadow_prefix.join(path);
 
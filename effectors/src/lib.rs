@@ -1,7 +1,11 @@
 use anyhow::Result;
 
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
+/// Metadata of a path, like: `mode`, `owner`, `group`.
+pub type Metadata = BTreeMap<String, String>;
+
 pub const HANDSHAKE_RQ: &str = "com.akavel.care.v2.rq";
 pub const HANDSHAKE_RS: &str = "com.akavel.care.v2.rs";
 
@@ -11,8 +15,12 @@ pub trait Callee {
         Self: Sized;
 
     fn detect(&mut self, path: &Path) -> Result<bool>;
-    fn gather(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()>;
-    fn affect(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()>;
+    /// Gathers contents of `path` into the shadow file, and returns actual
+    /// values of metadata with the given `keys`.
+    fn gather(&mut self, path: &Path, shadow_prefix: &Path, keys: &[String]) -> Result<Metadata>;
+    /// Affects `path` according to the shadow file, setting the given
+    /// metadata if the file exists.
+    fn affect(&mut self, path: &Path, shadow_prefix: &Path, meta: &Metadata) -> Result<()>;
 
     fn serve(args: std::env::Args) -> Result<()>
     where
@@ -44,27 +52,39 @@ pub trait Callee {
             let Some((cmd, args)) = line.split_once(' ') else {
                 bail!("expected command with args, got: {line:?}");
             };
-            let mut args = args.split(' ').map(urldecode_to_path);
+            let mut args = args.split(' ');
             match cmd {
                 "detect" => {
                     let Some(path) = args.next() else {
                         bail!("expected 1 arg to 'detect', got none");
                     };
-                    let res = c.detect(&path?)?;
+                    let res = c.detect(&urldecode_to_path(path)?)?;
                     writeln!(out, "detected {}", if res { "present" } else { "absent" })?;
                 }
                 "gather" => {
                     let Some((path1, path2)) = args.next_tuple() else {
                         bail!("expected 2 args to 'gather', got less");
                     };
-                    c.gather(&path1?, &path2?)?;
-                    writeln!(out, "gathered")?;
+                    let keys = args
+                        .map(|k| Ok(urlencoding::decode(k)?.into_owned()))
+                        .collect::<Result<Vec<_>>>()?;
+                    let meta = c.gather(
+                        &urldecode_to_path(path1)?,
+                        &urldecode_to_path(path2)?,
+                        &keys,
+                    )?;
+                    writeln!(out, "gathered{}", encode_metadata(&meta))?;
                 }
                 "affect" => {
                     let Some((path1, path2)) = args.next_tuple() else {
                         bail!("expected 2 args to 'affect', got less");
                     };
-                    c.affect(&path1?, &path2?)?;
+                    let meta = decode_metadata(args)?;
+                    c.affect(
+                        &urldecode_to_path(path1)?,
+                        &urldecode_to_path(path2)?,
+                        &meta,
+                    )?;
                     writeln!(out, "affected")?;
                 }
                 _ => bail!("unknown command: {cmd:?}"),
@@ -74,6 +94,28 @@ pub trait Callee {
     }
 }
 
+/// Encodes metadata as protocol args, each preceded by a space.
+pub fn encode_metadata(meta: &Metadata) -> String {
+    use urlencoding::encode;
+    let args = meta
+        .iter()
+        .map(|(k, v)| format!(" {}={}", encode(k), encode(v)));
+    args.collect()
+}
+
+/// Decodes metadata from protocol args of the form: `key=value`.
+pub fn decode_metadata<'a>(args: impl IntoIterator<Item = &'a str>) -> Result<Metadata> {
+    let mut meta = Metadata::new();
+    for arg in args.into_iter().filter(|a| !a.is_empty()) {
+        let Some((k, v)) = arg.split_once('=') else {
+            anyhow::bail!("expected metadata arg as key=value, got: {arg:?}");
+        };
+        let k = urlencoding::decode(k)?.into_owned();
+        meta.insert(k, urlencoding::decode(v)?.into_owned());
+    }
+    Ok(meta)
+}
+
 fn urldecode_to_path(s: &str) -> Result<PathBuf> {
     use std::str::FromStr;
     let decoded = urlencoding::decode(s)?;
@@ -2,20 +2,47 @@ use anyhow::{bail, Context, Result};
 use std::path::Path;
 use toml::macros::Deserialize;
 
-pub fn from_file(ncl_path: &Path) -> Result<toml::Table> {
+/// Returns the name of the top-level field selected from Nickel scripts for
+/// the current machine, in the form: `username@hostname`.
+pub fn machine_field() -> Result<String> {
     let username = whoami::username();
     let mut hostname = whoami::fallible::hostname()?;
     hostname.make_ascii_lowercase();
-    let field_path_raw = format!("{username}@{hostname}");
+    Ok(format!("{username}@{hostname}"))
+}
+
+/// Nickel files of the library shipped with care, importable from scripts
+/// by their paths here, like: `import "care/std.ncl"`.
+const LIBRARY: &[(&str, &str)] = &[("care/std.ncl", include_str!("../lib/care/std.ncl"))];
+
+/// Import path under which the [`LIBRARY`] files are kept in memory, and
+/// shown in Nickel diagnostics, like: `<care>/care/std.ncl`. Files next to
+/// the script take precedence.
+const LIBRARY_DIR: &str = "<care>";
+
+pub fn from_file(ncl_path: &Path) -> Result<toml::Table> {
+    let field_path_raw = machine_field()?;
 
     use nickel_lang_core::{
-        error::report::ErrorFormat, eval::cache::lazy::CBNCache, identifier::LocIdent,
-        pretty::ident_quoted, program::Program as Prog,
+        error::report::ErrorFormat,
+        eval::cache::lazy::CBNCache,
+        identifier::LocIdent,
+        pretty::ident_quoted,
+        program::{Input, Program as Prog},
     };
     let field_path = ident_quoted(&LocIdent::new(field_path_raw));
     // println!("FIELD: {field_path:?}");
     use std::io::stderr;
-    let mut prog = Prog::<CBNCache>::new_from_file(&ncl_path, stderr())?;
+    // Sources can only be added to the program's cache as its inputs, which
+    // get merged with the script. This is harmless, as only the machine's
+    // field is evaluated, and the library has no such field.
+    let library = LIBRARY.iter().map(|(name, content)| {
+        let path = Path::new(LIBRARY_DIR).join(name);
+        Input::Source(content.as_bytes(), path.into_os_string())
+    });
+    let inputs = std::iter::once(Input::Path(ncl_path.into())).chain(library);
+    let mut prog = Prog::<CBNCache>::new_from_inputs(inputs, stderr())?;
+    prog.add_import_paths(std::iter::once(LIBRARY_DIR));
     let res_field = prog.parse_field_path(field_path.clone());
     let Ok(field) = res_field else {
         prog.report(res_field.unwrap_err(), ErrorFormat::Text);
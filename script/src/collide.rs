@@ -0,0 +1,86 @@
+use anyhow::{bail, Result};
+use unicase::UniCase;
+use unicode_normalization::UnicodeNormalization as _;
+
+use std::collections::HashMap;
+
+use crate::Script;
+
+/// Form of a subpath in which paths clashing on a machine are equal.
+#[derive(PartialEq, Eq, Hash)]
+enum Folded {
+    Sensitive(String),
+    Insensitive(UniCase<String>),
+}
+
+/// Detects paths which differ, but would clash on a machine: ones differing
+/// only in Unicode normalization (like NFC vs. NFD forms), or only in case,
+/// unless the effector handling them is declared case-sensitive.
+pub struct Collisions<'s, 'a> {
+    script: &'s Script,
+    seen: HashMap<(&'a str, Folded), &'a str>,
+}
+
+impl<'s, 'a> Collisions<'s, 'a> {
+    pub fn new(script: &'s Script) -> Self {
+        Self {
+            script,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Records `path`, and returns a different path recorded earlier which
+    /// `path` collides with, if any.
+    pub fn insert(&mut self, path: &'a str) -> Option<&'a str> {
+        let (prefix, subpath) = self.script.split_path(path).unwrap_or(("", path));
+        let normalized = subpath.nfc().collect::<String>();
+        let folded = match self.script.case_sensitive.contains(prefix) {
+            true => Folded::Sensitive(normalized),
+            false => Folded::Insensitive(UniCase::new(normalized)),
+        };
+        let seen = *self.seen.entry((prefix, folded)).or_insert(path);
+        (seen != path).then_some(seen)
+    }
+}
+
+/// Fails on the first pair of `paths` which collide, as described for
+/// [`Collisions`].
+pub fn ensure_none<'a>(script: &Script, paths: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    let mut collisions = Collisions::new(script);
+    for path in paths {
+        if let Some(other) = collisions.insert(path) {
+            bail!(
+                "paths {other:?} and {path:?} would clash on the machine, as they differ only in case or Unicode normalization"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Effectors;
+
+    #[test]
+    fn collide_per_effector_case_sensitivity() {
+        let script = Script {
+            effectors: Effectors::from_iter(["c", "etc"].map(|k| (k.to_string(), vec![]))),
+            case_sensitive: ["etc".to_string()].into(),
+            ..<_>::default()
+        };
+        let mut collisions = Collisions::new(&script);
+        assert_eq!(collisions.insert("c/Windows/notepad.exe"), None);
+        assert_eq!(collisions.insert("c/Windows/notepad.exe"), None);
+        assert_eq!(
+            collisions.insert("c/WINDOWS/Notepad.exe"),
+            Some("c/Windows/notepad.exe")
+        );
+        assert_eq!(collisions.insert("etc/hosts"), None);
+        assert_eq!(collisions.insert("etc/HOSTS"), None);
+        assert_eq!(collisions.insert("etc/caf\u{e9}"), None);
+        assert_eq!(collisions.insert("etc/cafe\u{301}"), Some("etc/caf\u{e9}"));
+        assert!(ensure_none(&script, ["etc/a", "etc/A"]).is_ok());
+        assert!(ensure_none(&script, ["c/a", "c/A"]).is_err());
+    }
+}
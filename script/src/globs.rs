@@ -0,0 +1,117 @@
+use anyhow::{bail, Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// A set of glob patterns matched against slash-separated paths. A path is
+/// matched if any pattern matches either the path itself, or one of its
+/// parent directories; so e.g. `home` matches all paths below `home/`.
+///
+/// In patterns, `*` does not cross a `/`, while `**` does.
+#[derive(Debug, Clone, Default)]
+pub struct PathGlobs {
+    set: GlobSet,
+}
+
+impl PathGlobs {
+    pub fn new(patterns: impl IntoIterator<Item: AsRef<str>>) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("parsing glob pattern {pattern:?}"))?;
+            builder.add(glob);
+        }
+        Ok(Self {
+            set: builder.build()?,
+        })
+    }
+
+    /// Builds a set from patterns in the style of `.gitignore` files:
+    /// - a pattern without a slash, like `*.swp`, matches a file or directory
+    ///   name at any depth; except for a plain name without glob characters
+    ///   or trailing slash, like `home`, which matches only the first segment
+    ///   of paths, as in scripts written before glob patterns were supported,
+    /// - a pattern with a slash, like `home/.cache/**`, or with a leading
+    ///   slash, like `/tmp`, is matched against the whole path,
+    /// - a pattern with a trailing slash, like `cache/`, matches only
+    ///   directories.
+    ///
+    /// Negated patterns, starting with `!`, are not supported.
+    pub fn gitignore(patterns: impl IntoIterator<Item: AsRef<str>>) -> Result<Self> {
+        let mut globs = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            if pattern.starts_with('!') {
+                bail!("negated glob pattern {pattern:?} is not supported");
+            }
+            let (pattern, dir_only) = match pattern.strip_suffix('/') {
+                Some(p) => (p, true),
+                None => (pattern, false),
+            };
+            let mut glob = match pattern.strip_prefix('/') {
+                Some(p) => p.to_string(),
+                None if !dir_only && !pattern.contains(['/', '*', '?', '[', '{']) => {
+                    pattern.to_string()
+                }
+                None if !pattern.contains('/') => format!("**/{pattern}"),
+                None => pattern.to_string(),
+            };
+            if dir_only {
+                glob.push_str("/**");
+            }
+            globs.push(glob);
+        }
+        Self::new(globs)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let parents = path.match_indices('/').map(|(i, _)| &path[..i]);
+        parents.chain([path]).any(|p| self.set.is_match(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_path_or_parents() {
+        let globs = PathGlobs::new(["home/.config/**", "etc", "*/x.txt"]).unwrap();
+        assert!(globs.matches("home/.config/foo"));
+        assert!(globs.matches("home/.config/foo/bar"));
+        assert!(globs.matches("etc/hosts"));
+        assert!(globs.matches("etc"));
+        assert!(globs.matches("c/x.txt"));
+        assert!(!globs.matches("home/.bashrc"));
+        assert!(!globs.matches("etcetera/hosts"));
+        assert!(!globs.matches("c/d/x.txt"));
+        assert!(!PathGlobs::default().matches("etc"));
+    }
+
+    #[test]
+    fn matches_gitignore_style() {
+        let globs = PathGlobs::gitignore(["*.swp", "/tmp", "home/.cache/**", "build/"]).unwrap();
+        assert!(globs.matches("a.swp"));
+        assert!(globs.matches("home/.vim/a.swp"));
+        assert!(globs.matches("tmp/x"));
+        assert!(!globs.matches("home/tmp/x"));
+        assert!(globs.matches("home/.cache/x/y"));
+        assert!(!globs.matches("etc/home/.cache/x"));
+        assert!(globs.matches("home/build/out"));
+        assert!(!globs.matches("home/build"));
+        assert!(!globs.matches("home/.bashrc"));
+        assert!(PathGlobs::gitignore(["!keep"]).is_err());
+
+        // Plain names stay anchored at the first segment, like before globs.
+        let globs = PathGlobs::gitignore(["tmp", "*cache"]).unwrap();
+        assert!(globs.matches("tmp"));
+        assert!(globs.matches("tmp/x"));
+        assert!(!globs.matches("home/tmp"));
+        assert!(globs.matches("home/.cache/x"));
+    }
+}
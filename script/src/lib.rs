@@ -1,110 +1,182 @@
-use anyhow::{bail, Result};
+pub mod collide;
+pub mod globs;
+
+use collide::Collisions;
+use globs::PathGlobs;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use indexmap::IndexMap;
 use log::debug;
+use serde::Deserialize;
 use thiserror::Error;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
 
+pub use effectors::Metadata;
+pub use parse_ncl::machine_field;
+
+/// First segment of paths in the shadow repository reserved for care's own
+/// use, which are never passed to effectors. It is also used as the leaf
+/// marker in the tree, so no path in a script can have a segment so named.
+pub const RESERVED_PREFIX: &str = ".care";
+
 #[derive(Debug)]
 #[cfg_attr(test, derive(Default))]
 pub struct Script {
     pub shadow_dir: PathBuf,
-    pub ignores: Vec<String>,
+    /// Paths skipped by care, as if they were not present at all.
+    pub ignores: PathGlobs,
     pub effectors: Effectors,
     pub paths: PathContentMap,
+    /// Metadata of paths which have any declared in the script.
+    pub metadata: PathMetadataMap,
+    /// Nickel field paths in the script declaring each path, like
+    /// `tree.home.".bashrc"`; more than one if the path is duplicated.
+    pub fields: BTreeMap<String, Vec<String>>,
+    /// Prefixes of effectors handling paths case-sensitively; paths of
+    /// other effectors which differ only in case are considered clashing.
+    pub case_sensitive: BTreeSet<String>,
+    /// Path of the Nickel file the script was evaluated from.
+    pub ncl_path: PathBuf,
+    /// The evaluated script serialized as TOML, e.g. for fingerprinting.
+    pub evaluated: String,
 }
 
-pub type Effectors = BTreeMap<String, Vec<String>>;
-pub type PathContentMap = BTreeMap<String, String>;
+/// Schema of the record selected from a Nickel script for the machine.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawScript {
+    #[serde(default = "default_shadow_dir")]
+    shadow_dir: String,
+    #[serde(default)]
+    ignores: Vec<String>,
+    effectors: RawEffectors,
+    tree: toml::Table,
+}
+
+fn default_shadow_dir() -> String {
+    ".".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a record of effectors, or an array of records with one field each"
+)]
+enum RawEffectors {
+    Record(toml::Table),
+    Array(Vec<toml::Table>),
+}
+
+/// Commands of effectors, in the order in which paths are processed: as
+/// declared if listed in an array, or alphabetical if declared as a record.
+pub type Effectors = IndexMap<String, Vec<String>>;
+pub type PathContentMap = BTreeMap<String, Vec<u8>>;
+pub type PathMetadataMap = BTreeMap<String, Metadata>;
 
 impl Script {
     pub fn parse_ncl_file(ncl_path: &Path) -> Result<Self> {
-        let mut toml = parse_ncl::from_file(&ncl_path)?;
+        let toml = parse_ncl::from_file(ncl_path)?;
         let ncl_parent = if let Some(p) = ncl_path.parent() {
             p.to_owned()
         } else {
             PathBuf::from(".")
         };
-        Self::parse_toml(&mut toml, &ncl_parent)
+        let evaluated = toml.to_string();
+        let script = Self::parse_toml(toml, &ncl_parent)
+            .with_context(|| format!("loading script {ncl_path:?}"))?;
+        Ok(Script {
+            ncl_path: ncl_path.to_owned(),
+            evaluated,
+            ..script
+        })
     }
 
-    fn parse_toml(toml: &mut toml::Table, base_dir: &Path) -> Result<Self> {
+    fn parse_toml(toml: toml::Table, base_dir: &Path) -> Result<Self> {
         // println!("PARSED: {toml:?}");
+        let raw = RawScript::deserialize(toml).map_err(|err| {
+            // Errors from toml end with a line naming the field, like:
+            // "in `shadow_dir`"; join them into one line.
+            anyhow!("{}", err.to_string().trim_end().replace('\n', " "))
+        })?;
 
-        // Extract `shadow_dir` from toml
-        // TODO[LATER]: use serde instead to extract, maybe
-        let raw_shadow_dir = if let Some(dir) = toml.remove("shadow_dir") {
-            let toml::Value::String(dir) = dir else {
-                bail!("Expected 'shadow_dir' to be text, got: {dir:?}");
-            };
-            dir
-        } else {
-            ".".to_string()
-        };
-        // If raw_shadow_dir is absolute, join will ignore base_dir.
-        let shadow_dir = base_dir.join(&raw_shadow_dir);
-        debug!("SHAD: {shadow_dir:?} (from {raw_shadow_dir:?})");
-
-        let mut ignores = Vec::<String>::new();
-        if let Some(raw_ignores) = toml.remove("ignores") {
-            let toml::Value::Array(raw_ignores) = raw_ignores else {
-                bail!("Expected 'ignores' to be array, got: {raw_ignores:?}");
-            };
-            for (i, v) in raw_ignores.into_iter().enumerate() {
-                let toml::Value::String(s) = v else {
-                    bail!("Unexpected type of ignores[{i}], want String, got: {v:?}");
-                };
-                ignores.push(s);
-            }
-        }
+        // If shadow_dir is absolute, join will ignore base_dir.
+        let shadow_dir = base_dir.join(&raw.shadow_dir);
+        debug!("SHAD: {shadow_dir:?} (from {:?})", raw.shadow_dir);
 
-        // Extract `effectors` from toml
-        // TODO[LATER]: use serde instead to extract, maybe
-        let Some(raw_effectors) = toml.remove("effectors") else {
-            bail!("Missing 'effectors' in stdin");
-        };
-        let toml::Value::Table(raw_effectors) = raw_effectors else {
-            bail!("Expected 'effectors' to be table, got: {raw_effectors:?}");
-        };
+        let ignores = PathGlobs::gitignore(raw.ignores).context("in 'ignores'")?;
 
-        // Extract `tree` from toml
-        // TODO[LATER]: use serde instead to extract, maybe
-        let Some(raw_tree) = toml.remove("tree") else {
-            bail!("Missing 'tree' in stdin");
-        };
-        let toml::Value::Table(raw_tree) = raw_tree else {
-            bail!("Expected 'tree' to be table, got: {raw_tree:?}");
+        // Nickel does not keep the order of fields in records, so to declare
+        // the order of effectors, they can be listed as an array of records
+        // with one field each.
+        let raw_effectors = match raw.effectors {
+            RawEffectors::Record(t) => Vec::from_iter(t),
+            RawEffectors::Array(a) => {
+                let mut fields = Vec::new();
+                for (i, t) in a.into_iter().enumerate() {
+                    if t.len() != 1 {
+                        bail!("Expected effectors[{i}] to have exactly one field, got: {t}");
+                    }
+                    fields.extend(t);
+                }
+                fields
+            }
         };
+        let raw_tree = raw.tree;
 
-        // Convert effectors to a simple map
-        // TODO[LATER]: preserve original order
+        // Convert effectors to a map of commands
         let mut effectors = Effectors::new();
         for (k, v) in raw_effectors {
-            let toml::Value::String(s) = v else {
-                bail!("Unexpected type of effector {k:?}, want String, got: {v:?}");
-            };
-            effectors.insert(k, s.split_whitespace().map(str::to_string).collect());
+            let field = "effectors.".to_string() + &field_name(&k);
+            let cmd = effector_command(v).with_context(|| format!("at {field}"))?;
+            if effectors.insert(k, cmd).is_some() {
+                bail!("Duplicate effector at {field}");
+            }
         }
         debug!("HANDL: {effectors:?}");
 
         // Convert tree to paths map
+        // Duplicate paths, e.g. from `a."b/c"` and `a.b.c`, are reported
+        // later by `validate`, together with other problems.
         let mut paths = PathContentMap::new();
-        let mut todo = vec![(String::new(), raw_tree)];
+        let mut metadata = PathMetadataMap::new();
+        let mut fields = BTreeMap::<String, Vec<String>>::new();
+        let mut todo = vec![(String::new(), "tree".to_string(), raw_tree)];
         loop {
-            let Some((parent, subtree)) = todo.pop() else {
+            let Some((parent, parent_field, subtree)) = todo.pop() else {
                 break;
             };
             for (key, value) in subtree {
                 let path = parent.clone() + &key;
+                let field = parent_field.clone() + "." + &field_name(&key);
                 match value {
                     toml::Value::String(s) => {
-                        paths.insert(path, s);
+                        fields.entry(path.clone()).or_default().push(field);
+                        paths.insert(path, s.into_bytes());
                     }
                     toml::Value::Table(t) => {
-                        todo.push((path + "/", t));
+                        match leaf_of(&t).with_context(|| format!("at {field}"))? {
+                            Some(((key, value), meta)) => {
+                                let contents = read_leaf(key, value, base_dir)
+                                    .with_context(|| format!("at {field}"))?;
+                                let meta =
+                                    parse_metadata(meta).with_context(|| format!("at {field}"))?;
+                                for (suffix, content) in contents {
+                                    let path = path.clone() + &suffix;
+                                    fields.entry(path.clone()).or_default().push(field.clone());
+                                    if !meta.is_empty() {
+                                        metadata.insert(path.clone(), meta.clone());
+                                    }
+                                    paths.insert(path, content);
+                                }
+                            }
+                            None => todo.push((path + "/", field, t)),
+                        }
                     }
                     _ => {
-                        bail!("Unexpected type of value at {path:?} in tree: {value}");
+                        bail!("Unexpected type of value at {field}: {value}");
                     }
                 }
             }
@@ -115,36 +187,335 @@ impl Script {
         // }
 
         Ok(Script {
-            shadow_dir: shadow_dir.into(),
+            shadow_dir,
             ignores,
             effectors,
             paths,
+            metadata,
+            fields,
+            case_sensitive: BTreeSet::new(),
+            ncl_path: PathBuf::new(),
+            evaluated: String::new(),
         })
     }
 
-    pub fn validate(&self) -> ValidationResult {
+    /// Checks that all paths are canonical, unique, and handled by some
+    /// effector. Paths are considered duplicates also if they would clash on
+    /// the machine, as described for [`Collisions`]. Also checks that
+    /// effector prefixes are canonical paths, and their commands pass
+    /// `check_effector`. Neither paths nor prefixes may have a segment named
+    /// [`RESERVED_PREFIX`], and paths may have metadata only if their
+    /// effector's command passes `supports_metadata`. Reports all problems
+    /// found.
+    pub fn validate(
+        &self,
+        check_effector: impl Fn(&[String]) -> Result<()>,
+        supports_metadata: impl Fn(&[String]) -> bool,
+    ) -> ValidationResult {
         use ValidationError::*;
-        // TODO: instead, canonicalize path & detect diff, to also find `/../` etc.
-        // TODO: also, find dupes in paths, incl. case-insensitively
-        fn path_error_of(p: &String) -> Option<ValidationError> {
-            if p.ends_with("/") {
-                return Some(TrailingSlashInPath(p.clone()));
+        fn path_error_of(p: &str) -> Option<ValidationError> {
+            let segments = || p.split('/');
+            if p.chars().any(char::is_control) {
+                return Some(ControlCharInPath(p.to_string()));
+            } else if p.contains('\\') {
+                return Some(BackslashInPath(p.to_string()));
+            } else if p.starts_with('/') {
+                return Some(LeadingSlashInPath(p.to_string()));
+            } else if p.ends_with('/') {
+                return Some(TrailingSlashInPath(p.to_string()));
             } else if p.contains("//") {
-                return Some(DoubleSlashInPath(p.clone()));
-            } else if p.contains("/../") {
-                return Some(DoubleDotInPath(p.clone()));
+                return Some(DoubleSlashInPath(p.to_string()));
+            } else if segments().any(|s| s == "..") {
+                return Some(DoubleDotInPath(p.to_string()));
+            } else if segments().any(|s| s == ".") {
+                return Some(DotInPath(p.to_string()));
             }
             None
         }
-        if let Some(err) = self.paths.keys().flat_map(path_error_of).next() {
-            return Err(err);
+        let field_of = |p: &str, i: usize| {
+            let fields = self.fields.get(p).map(Vec::as_slice).unwrap_or_default();
+            fields.get(i).cloned().unwrap_or_else(|| "tree".to_string())
+        };
+        let mut errors = Vec::new();
+        for (prefix, cmd) in &self.effectors {
+            let field = "effectors.".to_string() + &field_name(prefix);
+            if prefix.is_empty() {
+                errors.push((field, InvalidEffectorPrefix(Box::new(EmptyPath))));
+            } else if let Some(err) = path_error_of(prefix) {
+                errors.push((field, InvalidEffectorPrefix(Box::new(err))));
+            } else if prefix.split('/').any(|s| s == RESERVED_PREFIX) {
+                errors.push((field, ReservedEffectorPrefix(prefix.to_string())));
+            } else if let Err(err) = check_effector(cmd) {
+                errors.push((field, InvalidEffector(format!("{err:#}"))));
+            }
+        }
+        let mut collisions = Collisions::new(self);
+        for p in self.paths.keys() {
+            if let Some(err) = path_error_of(p) {
+                errors.push((field_of(p, 0), err));
+                continue;
+            }
+            if p.split('/').any(|s| s == RESERVED_PREFIX) {
+                errors.push((field_of(p, 0), ReservedSegmentInPath(p.to_string())));
+                continue;
+            }
+            match self.split_path(p) {
+                None => errors.push((field_of(p, 0), NoEffectorForPath(p.to_string()))),
+                Some((prefix, _)) => {
+                    if self.metadata.contains_key(p) && !supports_metadata(&self.effectors[prefix])
+                    {
+                        let err = MetadataNotSupported(p.to_string(), prefix.to_string());
+                        errors.push((field_of(p, 0), err));
+                    }
+                }
+            }
+            for i in 1..self.fields.get(p).map_or(0, Vec::len) {
+                errors.push((field_of(p, i), DuplicatePath(p.to_string(), field_of(p, 0))));
+            }
+            if let Some(other) = collisions.insert(p) {
+                errors.push((
+                    field_of(p, 0),
+                    CollidingPaths(p.to_string(), other.to_string()),
+                ));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ValidationErrors(errors));
         }
         Ok(())
     }
 
+    /// Returns prefixes of declared effectors which handle no paths in the
+    /// script.
+    pub fn unused_effectors(&self) -> Vec<&str> {
+        let used = BTreeSet::from_iter(
+            self.paths
+                .keys()
+                .filter_map(|p| Some(self.split_path(p)?.0)),
+        );
+        let prefixes = self.effectors.keys().map(String::as_str);
+        prefixes.filter(|prefix| !used.contains(prefix)).collect()
+    }
+
+    /// Splits `path` into the prefix of the effector handling it, and the
+    /// subpath passed to this effector. Prefixes can have multiple segments,
+    /// like `etc/nginx`; the longest prefix matching `path` wins.
+    pub fn split_path<'a>(&self, path: &'a str) -> Option<(&'a str, &'a str)> {
+        self.effectors
+            .keys()
+            .filter_map(|prefix| {
+                let subpath = path.strip_prefix(prefix.as_str())?.strip_prefix('/')?;
+                Some((&path[..prefix.len()], subpath))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+    }
+
+    /// Returns the position of the effector handling `path` among declared
+    /// effectors, so that paths can be processed in that order.
+    pub fn effector_rank(&self, path: &str) -> usize {
+        self.split_path(path)
+            .and_then(|(prefix, _)| self.effectors.get_index_of(prefix))
+            .unwrap_or(usize::MAX)
+    }
+
     pub fn ignores_path(&self, path: &str) -> bool {
-        let first_segment_of_path = path.split('/').next().unwrap();
-        self.ignores.iter().any(|ign| ign == first_segment_of_path)
+        self.ignores.matches(path)
+    }
+}
+
+/// Converts an effector declaration to a command: the effector's kind,
+/// followed by its arguments. An effector can be declared as either:
+/// - a string, split on whitespace, like: `"*lua effectors.posixfiles /home"`,
+/// - an array of strings, like: `["*lua", "effectors.posixfiles", "/My Files"]`,
+/// - a record, like: `{ kind = "*scp", host = "pc", key_agent = true }`, with
+///   optional `args` array, and other fields converted to `--kebab-case`
+///   options; boolean options are passed as flags if true.
+fn effector_command(value: toml::Value) -> Result<Vec<String>> {
+    let strings = |values: toml::value::Array| -> Result<Vec<String>> {
+        let strings = values.into_iter().map(|v| match v {
+            toml::Value::String(s) => Ok(s),
+            _ => bail!("Expected an array of strings, got: {v}"),
+        });
+        strings.collect()
+    };
+    let table = match value {
+        toml::Value::String(s) => return Ok(s.split_whitespace().map(str::to_string).collect()),
+        toml::Value::Array(a) => {
+            let cmd = strings(a)?;
+            if cmd.is_empty() {
+                bail!("Expected a non-empty array");
+            }
+            return Ok(cmd);
+        }
+        toml::Value::Table(t) => t,
+        _ => bail!("Expected a string, array or record, got: {value}"),
+    };
+    // Note: not removing fields from `table`, as it would change their order.
+    let Some(toml::Value::String(kind)) = table.get("kind") else {
+        bail!("Expected 'kind' to be text in record");
+    };
+    let mut cmd = vec![kind.clone()];
+    match table.get("args") {
+        None => {}
+        Some(toml::Value::Array(args)) => cmd.extend(strings(args.clone()).context("in 'args'")?),
+        Some(args) => bail!("Expected 'args' to be an array, got: {args}"),
+    }
+    for (key, value) in table {
+        if key == "kind" || key == "args" {
+            continue;
+        }
+        let option = format!("--{}", key.replace('_', "-"));
+        match value {
+            toml::Value::String(s) => cmd.extend([option, s]),
+            toml::Value::Integer(i) => cmd.extend([option, i.to_string()]),
+            toml::Value::Float(f) => cmd.extend([option, f.to_string()]),
+            toml::Value::Boolean(true) => cmd.push(option),
+            toml::Value::Boolean(false) => {}
+            _ => bail!("Unexpected type of {key:?}, want String, Number or Bool, got: {value}"),
+        }
+    }
+    Ok(cmd)
+}
+
+/// Value of the [`RESERVED_PREFIX`] field marking a record in the tree as a
+/// leaf, rather than a subdirectory. As the prefix is reserved, no path can
+/// be named so, like: `{ ".care" = "leaf", file = "..." }`.
+const LEAF_MARKER: &str = "leaf";
+
+/// Keys of the leaf records in the tree, each holding the content of a path:
+/// - `content = "..."`: text content,
+/// - `base64 = "..."`: binary content encoded in base64,
+/// - `file = "..."`: content of a file, relative to the script,
+/// - `dir = "..."`: contents of all files in a directory, recursively,
+///   relative to the script.
+///
+/// A leaf record has exactly one of them, and any of the [`METADATA_KEYS`].
+const LEAF_KEYS: [&str; 4] = ["content", "base64", "file", "dir"];
+
+/// Keys of metadata of paths, allowed in leaf records.
+const METADATA_KEYS: [&str; 3] = ["mode", "owner", "group"];
+
+type Field<'a> = (&'a str, &'a str);
+
+/// Returns the content field and metadata fields of a leaf record in the
+/// tree, or `None` if `table` has no [`LEAF_MARKER`], being a subdirectory.
+/// A subdirectory named [`RESERVED_PREFIX`] in it is left to be reported by
+/// [`Script::validate`].
+fn leaf_of(table: &toml::Table) -> Result<Option<(Field, Vec<Field>)>> {
+    let Some(marker) = table.get(RESERVED_PREFIX).filter(|v| !v.is_table()) else {
+        return Ok(None);
+    };
+    if marker.as_str() != Some(LEAF_MARKER) {
+        bail!(
+            "Expected {RESERVED_PREFIX:?} to be {LEAF_MARKER:?}, got: {marker}; \
+             no path can be named {RESERVED_PREFIX:?}, as it is reserved"
+        );
+    }
+    let mut content = None;
+    let mut meta = Vec::new();
+    for (key, value) in table {
+        let key = key.as_str();
+        if key == RESERVED_PREFIX {
+            continue;
+        }
+        let toml::Value::String(value) = value else {
+            bail!("Expected {key:?} to be text in leaf record, got: {value}");
+        };
+        if LEAF_KEYS.contains(&key) {
+            if let Some((other, _)) = content {
+                bail!(
+                    "Expected only one of {LEAF_KEYS:?} in leaf record, got {other:?} and {key:?}"
+                );
+            }
+            content = Some((key, value.as_str()));
+        } else if METADATA_KEYS.contains(&key) {
+            meta.push((key, value.as_str()));
+        } else {
+            bail!("Unexpected field {key:?} in leaf record");
+        }
+    }
+    let Some(content) = content else {
+        bail!("Expected one of {LEAF_KEYS:?} in leaf record");
+    };
+    Ok(Some((content, meta)))
+}
+
+fn parse_metadata(fields: Vec<Field>) -> Result<Metadata> {
+    let mut meta = Metadata::new();
+    for (key, value) in fields {
+        let value = match key {
+            "mode" => {
+                let valid = (3..=4).contains(&value.len()) && value.chars().all(|c| c.is_digit(8));
+                if !valid {
+                    bail!("Expected 'mode' to be 3 or 4 octal digits, got: {value:?}");
+                }
+                format!("{value:0>4}")
+            }
+            _ if value.is_empty() => bail!("Expected {key:?} to be non-empty"),
+            _ => value.to_string(),
+        };
+        meta.insert(key.to_string(), value);
+    }
+    Ok(meta)
+}
+
+/// Reads the contents of a leaf record. Returns them as pairs of a path
+/// suffix, to be appended to the leaf's path, and content.
+fn read_leaf(key: &str, value: &str, base_dir: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    match key {
+        "content" => Ok(vec![(String::new(), value.as_bytes().to_vec())]),
+        "base64" => {
+            let content = BASE64_STANDARD
+                .decode(value)
+                .context("decoding 'base64' content")?;
+            Ok(vec![(String::new(), content)])
+        }
+        "file" => {
+            let file = base_dir.join(value);
+            let content = std::fs::read(&file).with_context(|| format!("reading {file:?}"))?;
+            Ok(vec![(String::new(), content)])
+        }
+        "dir" => {
+            let mut contents = Vec::new();
+            read_dir_files(&base_dir.join(value), String::new(), &mut contents)?;
+            Ok(contents)
+        }
+        _ => unreachable!("unknown leaf key {key:?}"),
+    }
+}
+
+fn read_dir_files(dir: &Path, prefix: String, out: &mut Vec<(String, Vec<u8>)>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("reading directory {dir:?}"))?;
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            bail!("File name is not valid UTF-8: {:?}", entry.path());
+        };
+        let suffix = prefix.clone() + "/" + &name;
+        let path = entry.path();
+        // Follow symlinks, like when reading a single file.
+        if std::fs::metadata(&path)?.is_dir() {
+            read_dir_files(&path, suffix, out)?;
+        } else {
+            let content = std::fs::read(&path).with_context(|| format!("reading {path:?}"))?;
+            out.push((suffix, content));
+        }
+    }
+    Ok(())
+}
+
+/// Renders a key of a record as a field name in a Nickel field path, quoting
+/// it if it is not a plain identifier.
+fn field_name(key: &str) -> String {
+    let mut chars = key.chars();
+    let plain = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_-'".contains(c));
+    if plain {
+        key.to_string()
+    } else {
+        format!("{key:?}")
     }
 }
 
@@ -154,11 +525,52 @@ pub enum ValidationError {
     DoubleSlashInPath(String),
     #[error("path `{0}` ends with a slash `/`")]
     TrailingSlashInPath(String),
-    #[error("path `{0}` contains double dot `/../`")]
+    #[error("path `{0}` starts with a slash `/`")]
+    LeadingSlashInPath(String),
+    #[error("path `{0}` contains double dot `..` segment")]
     DoubleDotInPath(String),
+    #[error("path `{0}` contains dot `.` segment")]
+    DotInPath(String),
+    #[error("path {0:?} contains backslash `\\`; use slash `/` to separate segments")]
+    BackslashInPath(String),
+    #[error("path {0:?} contains control characters")]
+    ControlCharInPath(String),
+    #[error("path `{0}` contains segment `.care`, reserved for care's own use")]
+    ReservedSegmentInPath(String),
+    #[error("path is empty")]
+    EmptyPath,
+    #[error("path `{0}` does not start with a prefix of any effector")]
+    NoEffectorForPath(String),
+    #[error("effector prefix is invalid: {0}")]
+    InvalidEffectorPrefix(Box<ValidationError>),
+    #[error("effector prefix `{0}` is reserved for care's own use")]
+    ReservedEffectorPrefix(String),
+    #[error("effector command is invalid: {0}")]
+    InvalidEffector(String),
+    #[error("path `{0}` has metadata, which its effector `{1}` does not support")]
+    MetadataNotSupported(String, String),
+    #[error("path `{0}` is already declared at {1}")]
+    DuplicatePath(String, String),
+    #[error("path `{0}` collides with `{1}`, differing only in case or Unicode normalization")]
+    CollidingPaths(String, String),
+}
+
+/// All problems found in a script, each with the Nickel field path where the
+/// offending path is declared.
+#[derive(Error, Debug)]
+pub struct ValidationErrors(pub Vec<(String, ValidationError)>);
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "found {} problem(s) in script:", self.0.len())?;
+        for (field, err) in &self.0 {
+            write!(f, "\n  at {field}: {err}")?;
+        }
+        Ok(())
+    }
 }
 
-type ValidationResult = std::result::Result<(), ValidationError>;
+type ValidationResult = std::result::Result<(), ValidationErrors>;
 
 #[cfg(test)]
 mod tests {
@@ -167,36 +579,378 @@ mod tests {
 
     #[test]
     fn validate_problems_in_paths() {
-        // validate a Script built with given paths
-        fn vsp<'a>(paths: impl IntoIterator<Item = &'a str>) -> ValidationResult {
+        // validate a Script built with given paths, and effectors for their
+        // first segments which are valid prefixes
+        fn vsp<'a>(paths: impl IntoIterator<Item = &'a str> + Clone) -> Vec<ValidationError> {
+            let prefixes = paths
+                .clone()
+                .into_iter()
+                .map(|s| s.split('/').next().unwrap())
+                .filter(|p| !p.is_empty() && !p.starts_with('.'));
             Script {
-                paths: paths
-                    .into_iter()
-                    .map(|s| (s.to_string(), "".to_string()))
-                    .collect(),
+                paths: paths.into_iter().map(|s| (s.to_string(), vec![])).collect(),
+                effectors: prefixes.map(|p| (p.to_string(), vec![])).collect(),
                 ..<_>::default()
             }
-            .validate()
+            .validate(|_| Ok(()), |_| true)
+            .map_or_else(
+                |errs| errs.0.into_iter().map(|(_, e)| e).collect(),
+                |()| vec![],
+            )
         }
 
         use ValidationError::*;
-        assert_matches!(vsp(["a/../b"]), Err(DoubleDotInPath(s)) if &s == "a/../b");
-        assert_matches!(vsp(["foo//bar"]), Err(DoubleSlashInPath(s)) if &s == "foo//bar");
+        assert_matches!(&vsp(["a/../b"])[..], [DoubleDotInPath(s)] if s == "a/../b");
+        assert_matches!(&vsp(["a/.."])[..], [DoubleDotInPath(s)] if s == "a/..");
+        assert_matches!(&vsp(["foo//bar"])[..], [DoubleSlashInPath(s)] if s == "foo//bar");
+        assert_matches!(
+            &vsp(["foo/bar//baz"])[..],
+            [DoubleSlashInPath(s)] if s == "foo/bar//baz"
+        );
+        assert_matches!(
+            &vsp(["ok_a/ok_b", "foo/bar//baz"])[..],
+            [DoubleSlashInPath(s)] if s == "foo/bar//baz"
+        );
+        assert_matches!(
+            &vsp(["ok_a/ok_b", "foo/bar/"])[..],
+            [TrailingSlashInPath(s)] if s == "foo/bar/"
+        );
+        assert_matches!(
+            &vsp(["ok_a/ok_b", "foo/"])[..],
+            [TrailingSlashInPath(s)] if s == "foo/"
+        );
+        assert_matches!(&vsp(["/a/b"])[..], [LeadingSlashInPath(_)]);
+        assert_matches!(&vsp(["./a/b"])[..], [DotInPath(_)]);
+        assert_matches!(&vsp(["a/./b"])[..], [DotInPath(_)]);
+        assert_matches!(&vsp(["a/b\\c"])[..], [BackslashInPath(_)]);
+        assert_matches!(&vsp(["a/b\nc"])[..], [ControlCharInPath(_)]);
+        assert_matches!(
+            &vsp(["a/x/../y", "b/.", "ok/ok"])[..],
+            [DoubleDotInPath(a), DotInPath(b)] if a == "a/x/../y" && b == "b/."
+        );
         assert_matches!(
-            vsp(["foo/bar//baz"]),
-            Err(DoubleSlashInPath(s)) if &s == "foo/bar//baz"
+            &vsp(["home/X", "home/x"])[..],
+            [CollidingPaths(a, b)] if a == "home/x" && b == "home/X"
         );
         assert_matches!(
-            vsp(["ok_a/ok_b", "foo/bar//baz"]),
-            Err(DoubleSlashInPath(s)) if &s == "foo/bar//baz"
+            &vsp(["home/caf\u{e9}", "home/cafe\u{301}"])[..],
+            [CollidingPaths(_, _)]
         );
         assert_matches!(
-            vsp(["ok_a/ok_b", "foo/bar/"]),
-            Err(TrailingSlashInPath(s)) if &s == "foo/bar/"
+            &vsp(["a/.care/b", "a/.care"])[..],
+            [ReservedSegmentInPath(a), ReservedSegmentInPath(b)] if a == "a/.care" && b == "a/.care/b"
         );
+        assert!(vsp(["ok_a/ok_b", "ok_a/ok_c"]).is_empty());
+    }
+
+    #[test]
+    fn validate_reserved_dir_in_tree() {
+        let toml: toml::Table = r#"
+            effectors = { home = "*zeroinstall" }
+            [tree.home]
+            ".care".y = "x"
+            "a/.care/b" = "z"
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        use ValidationError::*;
         assert_matches!(
-            vsp(["ok_a/ok_b", "foo/"]),
-            Err(TrailingSlashInPath(s)) if &s == "foo/"
+            &script.validate(|_| Ok(()), |_| true).unwrap_err().0[..],
+            [
+                (f1, ReservedSegmentInPath(p1)),
+                (f2, ReservedSegmentInPath(p2)),
+            ] if f1 == r#"tree.home.".care".y"# && p1 == "home/.care/y"
+                && f2 == r#"tree.home."a/.care/b""# && p2 == "home/a/.care/b"
+        );
+    }
+
+    #[test]
+    fn validate_reports_nickel_fields() {
+        let toml: toml::Table = r#"
+            effectors = { home = "*zeroinstall" }
+            [tree.home]
+            "a/b" = "x"
+            a = { b = "y" }
+            "my file/" = "z"
+            [tree.etc]
+            hosts = ""
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        let errs = script.validate(|_| Ok(()), |_| true).unwrap_err();
+        let mut fields = Vec::from_iter(errs.0.iter().map(|(field, _)| field.as_str()));
+        fields.sort();
+        assert_eq!(
+            fields,
+            [
+                r#"tree.etc.hosts"#,
+                r#"tree.home."my file/""#,
+                r#"tree.home.a.b"#
+            ]
+        );
+        assert!(errs
+            .to_string()
+            .starts_with("found 3 problem(s) in script:\n  at "));
+    }
+
+    #[test]
+    fn parse_tree_leaves() {
+        let mut toml: toml::Table = r#"
+            effectors = {}
+            [tree.home]
+            "a.txt" = "text"
+            "icon.png" = { ".care" = "leaf", base64 = "AAEC/w==" }
+            base64 = { "b.txt" = "in dir" }
+            "data" = { base64 = "not a leaf" }
+            "run.sh" = { ".care" = "leaf", content = "echo", mode = "755", owner = "root" }
+            [tree.bad]
+            "x" = { ".care" = "leaf", base64 = "not base64!" }
+        "#
+        .parse()
+        .unwrap();
+        let err = Script::parse_toml(toml.clone(), Path::new(".")).unwrap_err();
+        assert!(format!("{err:#}").contains("at tree.bad.x"));
+
+        for (leaf, want) in [
+            (
+                r#"{ ".care" = "leaf", content = "a", file = "b" }"#,
+                "only one of",
+            ),
+            (r#"{ ".care" = "leaf", mode = "644" }"#, "Expected one of"),
+            (
+                r#"{ ".care" = "leaf", content = "a", size = "1" }"#,
+                "Unexpected field",
+            ),
+            (r#"{ ".care" = "dir", content = "a" }"#, r#"to be "leaf""#),
+        ] {
+            let bad = format!("effectors = {{}}\ntree.bad.x = {leaf}")
+                .parse()
+                .unwrap();
+            let err = Script::parse_toml(bad, Path::new(".")).unwrap_err();
+            let err = format!("{err:#}");
+            assert!(err.contains("at tree.bad.x") && err.contains(want), "{err}");
+        }
+
+        toml["tree"].as_table_mut().unwrap().remove("bad");
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        let paths = Vec::from_iter(script.paths);
+        assert_eq!(
+            paths,
+            [
+                ("home/a.txt".to_string(), b"text".to_vec()),
+                ("home/base64/b.txt".to_string(), b"in dir".to_vec()),
+                ("home/data/base64".to_string(), b"not a leaf".to_vec()),
+                ("home/icon.png".to_string(), vec![0, 1, 2, 255]),
+                ("home/run.sh".to_string(), b"echo".to_vec()),
+            ]
+        );
+        let meta = Metadata::from([
+            ("mode".to_string(), "0755".to_string()),
+            ("owner".to_string(), "root".to_string()),
+        ]);
+        assert_eq!(
+            Vec::from_iter(script.metadata),
+            [("home/run.sh".to_string(), meta)]
+        );
+    }
+
+    #[test]
+    fn parse_tree_file_refs() {
+        let base = tempfile::tempdir().unwrap();
+        std::fs::write(base.path().join("single"), b"one").unwrap();
+        std::fs::write(base.path().join("plain.txt"), b"unused").unwrap();
+        std::fs::create_dir_all(base.path().join("dots/sub")).unwrap();
+        std::fs::write(base.path().join("dots/a"), b"a").unwrap();
+        std::fs::write(base.path().join("dots/sub/b"), b"b").unwrap();
+        let toml: toml::Table = r#"
+            effectors = {}
+            [tree.home]
+            "x" = { ".care" = "leaf", file = "single" }
+            ".config" = { ".care" = "leaf", dir = "dots" }
+            notes = { file = "plain.txt" }
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, base.path()).unwrap();
+        let paths = Vec::from_iter(script.paths);
+        assert_eq!(
+            paths,
+            [
+                ("home/.config/a".to_string(), b"a".to_vec()),
+                ("home/.config/sub/b".to_string(), b"b".to_vec()),
+                ("home/notes/file".to_string(), b"plain.txt".to_vec()),
+                ("home/x".to_string(), b"one".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_effector_commands() {
+        let toml: toml::Table = r#"
+            tree = {}
+            [effectors]
+            a = "*lua effectors.posixfiles /home"
+            b = ["*lua", "effectors.posixfiles", "/My Files"]
+            c = { kind = "*scp", host = "pc", key_agent = true, verbose = false, port = 22 }
+            d = { kind = "*lua", args = ["effectors.posixfiles", "/My Files"] }
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        let cmds = Vec::from_iter(script.effectors.values().map(|v| v.join("|")));
+        assert_eq!(
+            cmds,
+            [
+                "*lua|effectors.posixfiles|/home",
+                "*lua|effectors.posixfiles|/My Files",
+                "*scp|--host|pc|--key-agent|--port|22",
+                "*lua|effectors.posixfiles|/My Files",
+            ]
+        );
+
+        let toml: toml::Table = "tree = {}\neffectors.x = { host = \"pc\" }"
+            .parse()
+            .unwrap();
+        let err = Script::parse_toml(toml, Path::new(".")).unwrap_err();
+        assert!(format!("{err:#}").contains("at effectors.x"));
+    }
+
+    #[test]
+    fn effectors_keep_declared_order() {
+        let toml: toml::Table = r#"
+            tree = {}
+            effectors = [{ zz = "*zeroinstall" }, { aa = "*lua x" }]
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        assert_eq!(Vec::from_iter(script.effectors.keys()), ["zz", "aa"]);
+        assert_eq!(script.effector_rank("zz/x"), 0);
+        assert_eq!(script.effector_rank("aa/x"), 1);
+        assert_eq!(script.effector_rank("bb/x"), usize::MAX);
+    }
+
+    #[test]
+    fn split_paths_by_longest_prefix() {
+        let script = Script {
+            effectors: Effectors::from_iter(
+                ["etc", "etc/nginx", "home"].map(|k| (k.to_string(), vec![])),
+            ),
+            paths: PathContentMap::from([("noslash".to_string(), vec![])]),
+            ..<_>::default()
+        };
+        assert_eq!(script.split_path("etc/hosts"), Some(("etc", "hosts")));
+        assert_eq!(
+            script.split_path("etc/nginx/nginx.conf"),
+            Some(("etc/nginx", "nginx.conf"))
+        );
+        assert_eq!(script.split_path("etc/nginxy/a"), Some(("etc", "nginxy/a")));
+        assert_eq!(script.split_path("etc"), None);
+        assert_eq!(script.split_path("var/x"), None);
+        assert_eq!(script.effector_rank("etc/nginx/a"), 1);
+        assert_matches!(
+            &script.validate(|_| Ok(()), |_| true).unwrap_err().0[..],
+            [(_, ValidationError::NoEffectorForPath(s))] if s == "noslash"
+        );
+    }
+
+    #[test]
+    fn validate_metadata_support() {
+        let toml: toml::Table = r#"
+            effectors = { home = "*lua x", apps = "*zeroinstall" }
+            [tree.home]
+            a = { ".care" = "leaf", content = "", mode = "644" }
+            [tree.apps]
+            b = { ".care" = "leaf", content = "", mode = "644" }
+            c = ""
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        let supports = |cmd: &[String]| cmd[0] != "*zeroinstall";
+        assert_matches!(
+            &script.validate(|_| Ok(()), supports).unwrap_err().0[..],
+            [(f, ValidationError::MetadataNotSupported(p, e))]
+                if f == "tree.apps.b" && p == "apps/b" && e == "apps"
+        );
+    }
+
+    #[test]
+    fn validate_effectors() {
+        let toml: toml::Table = r#"
+            effectors = { home = "*lua x", "etc/" = "*lua y", ".care" = "*lua z", bad = "*nope", idle = "*lua w" }
+            tree = { home = { ".bashrc" = "" } }
+        "#
+        .parse()
+        .unwrap();
+        let script = Script::parse_toml(toml, Path::new(".")).unwrap();
+        let check = |cmd: &[String]| match cmd[0].as_str() {
+            "*lua" => Ok(()),
+            kind => bail!("unknown effector {kind:?}"),
+        };
+        use ValidationError::*;
+        assert_matches!(
+            &script.validate(check, |_| true).unwrap_err().0[..],
+            [
+                (f1, InvalidEffectorPrefix(err)),
+                (f2, ReservedEffectorPrefix(_)),
+                (f3, InvalidEffector(msg)),
+            ] if f1 == r#"effectors."etc/""# && matches!(**err, TrailingSlashInPath(_))
+                && f2 == r#"effectors.".care""#
+                && f3 == "effectors.bad" && msg == r#"unknown effector "*nope""#
+        );
+        assert_eq!(script.unused_effectors(), ["etc/", ".care", "bad", "idle"]);
+    }
+
+    #[test]
+    fn parse_strict_schema() {
+        let err_of = |src: &str| {
+            let toml: toml::Table = src.parse().unwrap();
+            let err = Script::parse_toml(toml, Path::new(".")).unwrap_err();
+            format!("{err:#}")
+        };
+        assert_eq!(
+            err_of("efectors = {}\ntree = {}"),
+            "unknown field `efectors`, expected one of `shadow_dir`, `ignores`, `effectors`, `tree`"
+        );
+        assert_eq!(
+            err_of("shadow_dir = 1\neffectors = {}\ntree = {}"),
+            "invalid type: integer `1`, expected a string in `shadow_dir`"
+        );
+        assert_eq!(err_of("effectors = {}"), "missing field `tree`");
+        assert_eq!(
+            err_of("effectors = 1\ntree = {}"),
+            "expected a record of effectors, or an array of records with one field each in `effectors`"
+        );
+    }
+
+    #[test]
+    fn parse_ncl_with_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let ncl_path = dir.path().join("care.ncl");
+        let script = r#"
+            let care = import "care/std.ncl" in
+            {
+              MACHINE = {
+                effectors = { home = care.lua "effectors.posixfiles" ["/home/me"] },
+                tree.home."bin/hi" = care.sh "echo hi",
+                tree.home.secret = care.text "pass" & { mode = "0600" },
+              } | care.Script,
+            }
+        "#;
+        let machine = format!("{:?}", machine_field().unwrap());
+        std::fs::write(&ncl_path, script.replace("MACHINE", &machine)).unwrap();
+
+        let script = Script::parse_ncl_file(&ncl_path).unwrap();
+        assert_eq!(
+            script.effectors["home"],
+            ["*lua", "effectors.posixfiles", "/home/me"]
         );
+        assert_eq!(script.paths["home/bin/hi"], b"#!/bin/sh\necho hi\n");
+        assert_eq!(script.metadata["home/bin/hi"]["mode"], "0755");
+        assert_eq!(script.paths["home/secret"], b"pass");
+        assert_eq!(script.metadata["home/secret"]["mode"], "0600");
     }
 }
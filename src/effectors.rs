@@ -1,4 +1,4 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fn_error_context::context;
 use path_slash::PathBufExt as _;
 use phf::phf_set;
@@ -8,8 +8,11 @@ use std::io::{BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 
+use effectors::Metadata;
 use script::Effectors as Spec;
 
+use crate::report::{self, PathEvent};
+
 pub fn serve(mut args: std::env::Args) -> Result<()> {
     let Some(name) = args.next() else {
         bail!("subcommand 'effector' requires name of effector");
@@ -29,6 +32,44 @@ static EFFECTORS: phf::Set<&'static str> = phf_set! {
     "*zeroinstall",
 };
 
+/// Checks that `cmd` names a known effector, followed by valid arguments.
+pub fn validate(cmd: &[String]) -> Result<()> {
+    let Some((kind, args)) = cmd.split_first() else {
+        bail!("empty effector command");
+    };
+    if !EFFECTORS.contains(kind) {
+        bail!("unknown effector command: {cmd:?}");
+    }
+    match (kind.as_str(), args) {
+        ("*lua", []) => bail!("*lua requires an argument: name of a Lua-based effector package"),
+        ("*scp", _) => {
+            use clap::Parser as _;
+            if let Err(err) = f_scp::Args::try_parse_from(cmd) {
+                // Keep only the first paragraph, without clap's usage hints.
+                let err = err.to_string();
+                let err = err.lines().take_while(|l| !l.is_empty());
+                let err = err.map(str::trim).collect::<Vec<_>>().join(" ");
+                bail!(
+                    "invalid *scp arguments: {}",
+                    err.trim_start_matches("error: ")
+                );
+            }
+        }
+        ("*zeroinstall", [_, ..]) => bail!("*zeroinstall takes no arguments, got: {args:?}"),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Checks if the effector of `cmd` can handle metadata of paths. Lua-based
+/// effectors are assumed to, as it depends on the package.
+pub fn supports_metadata(cmd: &[String]) -> bool {
+    !matches!(
+        cmd.first().map(String::as_str),
+        Some("*scp" | "*zeroinstall")
+    )
+}
+
 type ChildProcs = BTreeMap<String, ChildProc>;
 
 pub struct ChildProc {
@@ -86,30 +127,43 @@ impl ChildProc {
         }
     }
 
-    pub fn gather(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    pub fn gather(
+        &mut self,
+        path: &Path,
+        shadow_prefix: &Path,
+        keys: &[String],
+    ) -> Result<Metadata> {
         use urlencoding::encode;
         let mut child_in = self.proc.stdin.as_ref().unwrap();
         writeln!(
             child_in,
-            "gather {} {}",
+            "gather {} {}{}",
             encode(path.to_str().unwrap()),
-            encode(shadow_prefix.to_str().unwrap())
+            encode(shadow_prefix.to_str().unwrap()),
+            keys.iter()
+                .map(|k| " ".to_owned() + &encode(k))
+                .collect::<String>(),
         )?;
         let rs = self.read_line()?;
-        if !rs.starts_with("gathered") {
+        let Some(args) = rs.trim_end().strip_prefix("gathered") else {
             bail!("unexpected 'gather' response: {:?}", rs);
+        };
+        let meta = effectors::decode_metadata(args.split(' '))?;
+        if let Some(key) = keys.iter().find(|k| !meta.contains_key(*k)) {
+            bail!("effector did not report metadata {key:?}");
         }
-        Ok(())
+        Ok(meta)
     }
 
-    pub fn affect(&mut self, path: &Path, shadow_prefix: &Path) -> Result<()> {
+    pub fn affect(&mut self, path: &Path, shadow_prefix: &Path, meta: &Metadata) -> Result<()> {
         use urlencoding::encode;
         let mut child_in = self.proc.stdin.as_ref().unwrap();
         writeln!(
             child_in,
-            "affect {} {}",
+            "affect {} {}{}",
             encode(path.to_str().unwrap()),
-            encode(shadow_prefix.to_str().unwrap())
+            encode(shadow_prefix.to_str().unwrap()),
+            effectors::encode_metadata(meta),
         )?;
         let rs = self.read_line()?;
         if !rs.starts_with("affected") {
@@ -125,18 +179,20 @@ pub struct Effectors {
 
 impl Effectors {
     pub fn init(spec: &Spec) -> Result<Effectors> {
+        // Validate all before spawning any, to not leave some half-started.
+        for (root, cmd) in spec {
+            validate(cmd).with_context(|| format!("in effector {root:?}"))?;
+        }
         let mut child_procs = ChildProcs::new();
         for (root, cmd) in spec {
-            println!("care:   {root}");
-            match &cmd[..] {
-                [s, args @ ..] if EFFECTORS.contains(s) => {
-                    // TODO[LATER]: check no duplicates
-                    child_procs.insert(root.clone(), ChildProc::new_effector(s, args)?);
-                }
-                _ => {
-                    bail!("unknown effector command: {cmd:?}");
-                }
-            }
+            let event = PathEvent {
+                prefix: root,
+                action: "start",
+                ..Default::default()
+            };
+            let child = report::path(event, || ChildProc::new_effector(&cmd[0], &cmd[1..]))?;
+            // TODO[LATER]: check no duplicates
+            child_procs.insert(root.clone(), child);
         }
         Ok(Self { child_procs })
     }
@@ -148,19 +204,39 @@ impl Effectors {
     }
 
     #[context("gathering at {prefix}/{subpath}")]
-    pub fn gather(&mut self, prefix: &str, subpath: &str, shadow_root: &Path) -> Result<()> {
+    pub fn gather(
+        &mut self,
+        prefix: &str,
+        subpath: &str,
+        shadow_root: &Path,
+        keys: &[String],
+    ) -> Result<Metadata> {
         let subpath = &PathBuf::from_slash(subpath);
-        self.for_prefix(prefix)?
-            .gather(subpath, &shadow_root.join(prefix))
+        self.for_prefix(prefix)?.gather(
+            subpath,
+            &shadow_root.join(PathBuf::from_slash(prefix)),
+            keys,
+        )
     }
 
     #[context("affecting at {prefix}/{subpath}")]
-    pub fn affect(&mut self, prefix: &str, subpath: &str, shadow_root: &Path) -> Result<()> {
+    pub fn affect(
+        &mut self,
+        prefix: &str,
+        subpath: &str,
+        shadow_root: &Path,
+        meta: &Metadata,
+    ) -> Result<()> {
         let subpath = &PathBuf::from_slash(subpath);
-        self.for_prefix(prefix)?
-            .affect(subpath, &shadow_root.join(prefix))
+        self.for_prefix(prefix)?.affect(
+            subpath,
+            &shadow_root.join(PathBuf::from_slash(prefix)),
+            meta,
+        )
     }
 
+    /// Returns the effector at exactly `prefix`; find the longest prefix of a
+    /// path with [`script::Script::split_path`] first.
     fn for_prefix(&mut self, prefix: &str) -> Result<&mut ChildProc> {
         let Some(v) = self.child_procs.get_mut(prefix) else {
             bail!("effector not found for prefix {prefix:?}");
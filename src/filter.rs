@@ -0,0 +1,23 @@
+use anyhow::Result;
+use script::globs::PathGlobs;
+
+/// Restricts which paths get processed, based on glob patterns of paths to
+/// process exclusively, and of paths to skip.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    only: PathGlobs,
+    exclude: PathGlobs,
+}
+
+impl PathFilter {
+    pub fn new(only: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            only: PathGlobs::new(only)?,
+            exclude: PathGlobs::new(exclude)?,
+        })
+    }
+
+    pub fn accepts(&self, path: &str) -> bool {
+        (self.only.is_empty() || self.only.matches(path)) && !self.exclude.matches(path)
+    }
+}
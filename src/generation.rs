@@ -0,0 +1,64 @@
+use anyhow::Result;
+use script::Script;
+
+use std::time::SystemTime;
+
+use crate::repo::Repo;
+
+const SUBJECT_PREFIX: &str = "care: generation ";
+
+/// Returns the generation number recorded in a commit message, if any.
+pub fn number_of(message: &str) -> Option<u64> {
+    let rest = message.strip_prefix(SUBJECT_PREFIX)?;
+    let digits = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+/// Returns the number of the latest generation recorded in the repository,
+/// or 0 if there's none yet.
+pub fn latest(repo: &Repo) -> Result<u64> {
+    let found = repo.find_in_history(|msg| number_of(msg).is_some())?;
+    Ok(found.and_then(|(_, msg)| number_of(&msg)).unwrap_or(0))
+}
+
+/// Returns the ID of the commit recording generation `number`, if found.
+pub fn find(repo: &Repo, number: u64) -> Result<Option<git2::Oid>> {
+    let found = repo.find_in_history(|msg| number_of(msg) == Some(number))?;
+    Ok(found.map(|(oid, _)| oid))
+}
+
+/// Commits the index of the shadow repository as a new generation, with
+/// a message describing where and from what script it was applied. An
+/// optional `note` is appended to the subject line. Returns the number of
+/// the new generation, or `None` if the index did not change.
+pub fn commit(repo: &Repo, script: &Script, note: Option<&str>) -> Result<Option<u64>> {
+    let number = latest(repo)? + 1;
+    let mut message = format!("{SUBJECT_PREFIX}{number}");
+    if let Some(note) = note {
+        message += &format!(" ({note})");
+    }
+    let machine = script::machine_field()?;
+    let (user, host) = machine.rsplit_once('@').unwrap_or(("", &machine));
+    let time = humantime::format_rfc3339_seconds(SystemTime::now());
+    let ncl_path = std::fs::canonicalize(&script.ncl_path).unwrap_or(script.ncl_path.clone());
+    let script_hash = git2::Oid::hash_object(git2::ObjectType::Blob, script.evaluated.as_bytes())?;
+    message += &format!(
+        "\n\nHost: {host}\nUser: {user}\nTime: {time}\nScript: {}\nScript-Hash: {script_hash}\n",
+        ncl_path.display(),
+    );
+    Ok(repo.commit_index(&message)?.map(|_| number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_of_message() {
+        assert_eq!(number_of("care: generation 12\n\nHost: x\n"), Some(12));
+        assert_eq!(number_of("care: generation 3 (rollback to 1)"), Some(3));
+        assert_eq!(number_of("care: init"), None);
+        assert_eq!(number_of("care: generation x"), None);
+        assert_eq!(number_of("fixed typo"), None);
+    }
+}
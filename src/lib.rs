@@ -1,2 +1,10 @@
 pub mod effectors;
+pub mod filter;
+pub mod generation;
+pub mod metadata;
+pub mod metrics;
+pub mod ncl;
+pub mod plan;
+pub mod reconcile;
 pub mod repo;
+pub mod report;
@@ -2,17 +2,26 @@ use anyhow::{bail, Context, Result};
 use cap_std::ambient_authority;
 use cap_std::fs::Dir;
 use clap::{Parser, Subcommand};
-use log::debug;
+use itertools::Itertools as _;
+use log::{debug, warn};
 use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::SystemTime;
 // Trait for extending std::path::PathBuf
 use path_slash::PathBufExt as _;
-use unicase::UniCase;
 
-use script::Script;
+use script::{collide, Metadata, Script};
 
 use care::effectors::{self, Effectors};
+use care::filter::PathFilter;
+use care::metadata;
+use care::metrics;
+use care::ncl;
+use care::plan::{Change, PendingPath, SavedPlan};
+use care::reconcile::{PathState, Tally};
 use care::repo::Repo;
+use care::report::{self, Format, PathEvent};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,6 +30,27 @@ struct Cli {
     #[arg(short, long, default_value = "care.ncl")]
     ncl: PathBuf,
 
+    /// Only process paths matching a glob pattern, like: `home/.config/**`,
+    /// or paths below a matching directory, like: `home`. Can be repeated.
+    #[arg(long, global = true, value_name = "GLOB")]
+    only: Vec<String>,
+
+    /// Skip paths matching a glob pattern, or paths below a matching
+    /// directory. Can be repeated.
+    #[arg(long, global = true, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Treat paths of the effector at a prefix as case-sensitive, like on
+    /// posix filesystems, so that paths differing only in case can coexist.
+    /// By default, such paths are considered clashing, like on Windows and
+    /// macOS. Can be repeated.
+    #[arg(long, global = true, value_name = "PREFIX")]
+    case_sensitive: Vec<String>,
+
+    /// Format of the progress output.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: Format,
+
     /// Turn debugging information on.
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
@@ -31,29 +61,107 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Command {
+    /// Create a starter Nickel script for this machine (unless the file
+    /// already exists), and an empty git repository at its 'shadow_dir'.
+    Init {
+        /// Value of 'shadow_dir' to put in the starter script, relative
+        /// to the script's directory.
+        #[arg(long, default_value = "shadow")]
+        shadow_dir: String,
+    },
     /// Check actual state of the machine and serialize it into git
-    /// working directory at 'shadow_dir'.
+    /// working directory at 'shadow_dir'. Exits with code 0 if the machine
+    /// matches the last generation, 2 if any paths drifted, or 1 on error.
     #[command(alias = "c")]
-    Check,
+    Check {
+        /// Also write results to a file in Prometheus text format, for
+        /// the node exporter's textfile collector. The file is written
+        /// even if the check fails.
+        #[arg(long, value_name = "FILE")]
+        prometheus: Option<PathBuf>,
+        /// Check also if changes written by 'draft' are not applied yet,
+        /// overwriting them.
+        #[arg(long)]
+        force: bool,
+    },
     /// Serialize desired state (as read from input) into git working
     /// directory at 'shadow_dir'.
     #[command(alias = "d")]
     Draft,
     /// Apply the contents of the git working directory to the state
     /// of the machine. For each successfully applied file, perform
-    /// `git add` on it.
+    /// `git add` on it. Finally, commit the result as a new generation, also
+    /// if stopped early by an error, leaving the remaining files pending.
     #[command(alias = "a")]
-    Apply,
+    Apply {
+        /// Only show what would be applied, same as the 'plan' subcommand.
+        #[arg(long, conflicts_with = "plan")]
+        dry_run: bool,
+        /// Refuse to apply unless the pending paths and their contents
+        /// exactly match a plan saved earlier with `plan -o`.
+        #[arg(long)]
+        plan: Option<PathBuf>,
+        /// Show each pending path with its diff, and ask whether to apply
+        /// it. Paths not applied stay pending in the git working directory.
+        #[arg(short, long, conflicts_with = "dry_run")]
+        interactive: bool,
+    },
+    /// Run 'check', 'draft' and 'apply' in sequence, stopping at the first
+    /// phase that fails.
+    #[command(alias = "s")]
+    Sync {
+        /// Show the plan and ask for confirmation before applying it.
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// Restore the machine to the state recorded in an earlier generation,
+    /// by checking it out into the git working directory and applying it.
+    /// The result is recorded as a new generation.
+    Rollback {
+        /// Number of the generation to restore.
+        generation: u64,
+    },
+    /// Gather current contents of unmanaged paths from the machine, and
+    /// print them as a Nickel record of 'tree' entries, ready to be merged
+    /// into the script.
+    Adopt {
+        /// Paths to adopt, starting with an effector prefix, like:
+        /// `home/.bashrc`.
+        #[arg(required = true, value_name = "PATH")]
+        paths: Vec<String>,
+        /// Write the Nickel record to a file instead of printing it.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Stop managing paths without touching them on the machine: remove
+    /// them from the shadow repository, and record that as a new generation.
+    /// The paths must already be removed from the script.
+    Forget {
+        /// Paths to forget, like: `home/.bashrc`.
+        #[arg(required = true, value_name = "PATH")]
+        paths: Vec<String>,
+    },
+    /// Show what 'apply' would do: list paths pending in the git working
+    /// directory, grouped by effector, with diffs of their contents.
+    #[command(alias = "p")]
+    Plan {
+        /// Save the plan to a JSON file, for use with `apply --plan`.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
-fn main() -> Result<()> {
+/// Exit code of `care check` when some paths drifted.
+const EXIT_DRIFT: u8 = 2;
+
+fn main() -> Result<ExitCode> {
     // Handle hidden (internal) subcommand: `effector`.
     // If used, pass all subsequent args to it.
     {
         let mut args = std::env::args();
         if args.nth(1).as_deref() == Some("effector") {
             effectors::serve(args)?;
-            return Ok(());
+            return Ok(ExitCode::SUCCESS);
         }
     }
 
@@ -66,132 +174,367 @@ fn main() -> Result<()> {
     };
     env_logger::Builder::new().filter_level(log_level).init();
     debug!("Hello, world!");
-
-    println!("care: Processing Nickel script");
-    let script = Script::parse_ncl_file(&cli.ncl)?;
-    script.validate()?;
-    match &cli.command {
-        Command::Check => check(script),
-        Command::Draft => draft(script),
-        Command::Apply => apply(script),
+    report::set_format(cli.format);
+
+    let ncl = &cli.ncl;
+    let filter = &PathFilter::new(&cli.only, &cli.exclude)?;
+    let prompts = match &cli.command {
+        Command::Apply { interactive, .. } => *interactive,
+        Command::Sync { confirm } => *confirm,
+        _ => false,
+    };
+    if prompts && report::is_json() {
+        bail!("interactive prompts are not supported with --format json");
+    }
+    if matches!(&cli.command, Command::Adopt { output: None, .. }) {
+        if report::is_json() {
+            bail!("'adopt' requires --output with --format json");
+        }
+        // Keep stdout for the Nickel record only.
+        report::set_stderr();
     }
+    if let Command::Check { prometheus, force } = &cli.command {
+        let res = load_script(ncl, &cli.case_sensitive)
+            .and_then(|script| report::phase("check", || check(script, filter, *force)));
+        if let Some(file) = prometheus {
+            let metrics = metrics::render(res.as_ref().ok(), SystemTime::now());
+            metrics::write_textfile(file, &metrics)?;
+        }
+        return Ok(match res?.has_drift() {
+            true => ExitCode::from(EXIT_DRIFT),
+            false => ExitCode::SUCCESS,
+        });
+    }
+    match &cli.command {
+        Command::Init { shadow_dir } => report::phase("init", || init(ncl, shadow_dir)),
+        Command::Check { .. } => unreachable!(),
+        Command::Draft => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("draft", || draft(script, filter))
+        }
+        Command::Apply {
+            dry_run: false,
+            plan: plan_file,
+            interactive,
+        } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("apply", || {
+                apply(script, filter, plan_file.as_deref(), *interactive)
+            })
+        }
+        Command::Apply { dry_run: true, .. } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("plan", || plan(script, filter, None))
+        }
+        Command::Sync { confirm } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("sync", || sync(script, filter, *confirm))
+        }
+        Command::Rollback { generation } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("rollback", || rollback(script, filter, *generation))
+        }
+        Command::Adopt { paths, output } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("adopt", || adopt(script, paths, output.as_deref()))
+        }
+        Command::Forget { paths } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("forget", || forget(script, paths))
+        }
+        Command::Plan { output } => {
+            let script = load_script(ncl, &cli.case_sensitive)?;
+            report::phase("plan", || plan(script, filter, output.as_deref()))
+        }
+    }?;
+    Ok(ExitCode::SUCCESS)
 
     // TODO[LATER]: licensing information in --license flag
 }
 
-fn check(script: Script) -> Result<()> {
-    println!("care: Opening shadow repository");
-    let repo = Repo::open(&script.shadow_dir)?;
-    // check if repo is clean
-    if !repo.statuses_are_empty(&script.ignores)? {
-        bail!("git 'shadow_dir' repository is not clean (see: git status)");
+fn load_script(ncl_path: &Path, case_sensitive: &[String]) -> Result<Script> {
+    report::phase("load", || {
+        report::step("Processing Nickel script");
+        let mut script = Script::parse_ncl_file(ncl_path)?;
+        for prefix in case_sensitive {
+            if !script.effectors.contains_key(prefix) {
+                bail!("no effector for prefix {prefix:?} given in --case-sensitive");
+            }
+        }
+        script.case_sensitive = case_sensitive.iter().cloned().collect();
+        script.validate(effectors::validate, effectors::supports_metadata)?;
+        for prefix in script.unused_effectors() {
+            warn!("effector {prefix:?} is not used by any path in 'tree'");
+        }
+        Ok(script)
+    })
+}
+
+fn init(ncl_path: &Path, shadow_dir: &str) -> Result<()> {
+    if ncl_path.exists() {
+        report::step(&format!("Keeping existing Nickel script {ncl_path:?}"));
+    } else {
+        report::step(&format!("Writing starter Nickel script {ncl_path:?}"));
+        let machine = script::machine_field()?;
+        let starter = STARTER_SCRIPT
+            .replace("{machine}", &ncl::quote(&machine))
+            .replace("{shadow_dir}", &ncl::quote(shadow_dir));
+        std::fs::write(ncl_path, starter).with_context(|| format!("writing {ncl_path:?}"))?;
+    }
+
+    let script = load_script(ncl_path, &[])?;
+    if Repo::open(&script.shadow_dir).is_ok() {
+        report::step(&format!(
+            "Keeping existing shadow repository {:?}",
+            script.shadow_dir
+        ));
+        return Ok(());
     }
+    report::step(&format!(
+        "Creating shadow repository {:?}",
+        script.shadow_dir
+    ));
+    Repo::init(&script.shadow_dir)?;
+    Ok(())
+}
 
-    // Initialize effectors
-    println!("care: Starting effectors:");
-    let mut effectors = Effectors::init(&script.effectors)?;
+const STARTER_SCRIPT: &str = r#"# Contracts and helpers shipped with care, like: care.lua, care.sh.
+let care = import "care/std.ncl" in
+{
+  {machine} = {
+    shadow_dir = {shadow_dir},
+    # Paths to skip, as patterns like in .gitignore, e.g. "*.swp", "home/.cache/";
+    # a plain name, like "tmp", matches only the first segment of paths.
+    ignores = [],
+    # Paths are processed in alphabetical order of their effectors; to choose
+    # the order, use an array instead, like: [{ apps = ... }, { home = ... }].
+    effectors = {
+      # Each path in 'tree' is handled by the effector named by the longest
+      # prefix of the path. For example:
+      # home = "*lua effectors.winhome",
+      # apps = "*zeroinstall",
+      # "etc/nginx" = "*lua effectors.posixfiles /etc/nginx",
+      # Arguments with spaces can be passed as an array, or a record:
+      # files = ["*lua", "effectors.posixfiles", "/home/me/My Files"],
+      # remote = { kind = "*scp", host = "pc", user = "me", key_agent = true },
+    },
+    tree = {
+      # home.".gitconfig" = m%"
+      #   [user]
+      #   name = Jane Doe
+      # "%,
+      # Contents can also be read from files next to this script:
+      # home.".vimrc" = care.file "dotfiles/.vimrc",
+      # home.".config" = care.dir "dotfiles/.config",
+      # Metadata can be declared for effectors which support it:
+      # home."bin/hello" = care.text "echo hello" & { mode = "0755" },
+    },
+  } | care.Script,
+}
+"#;
+
+fn check(script: Script, filter: &PathFilter, force: bool) -> Result<Tally> {
+    let repo = open_repo(&script)?;
+    let mut effectors = start_effectors(&script)?;
+    run_check(&script, filter, &repo, &mut effectors, force)
+}
+
+/// Gathers paths from the machine into 'shadow_dir', and compares them with
+/// the last generation and the script. Refuses to overwrite changes written
+/// by 'draft' which are not applied yet, unless `force`.
+fn run_check(
+    script: &Script,
+    filter: &PathFilter,
+    repo: &Repo,
+    effectors: &mut Effectors,
+    force: bool,
+) -> Result<Tally> {
+    // Changes left in managed paths, e.g. by an earlier check which found
+    // drift, get overwritten by gathering the paths anew.
+    if !repo_has_only_managed_changes(repo, script)? {
+        bail!("git 'shadow_dir' repository is not clean (see: git status)");
+    }
+    if repo.is_drafted() && !repo_is_clean(repo, script)? {
+        if !force {
+            bail!(
+                "git 'shadow_dir' repository has changes from 'draft' which are not applied yet (see: git status); apply them, or overwrite them with 'check --force'"
+            );
+        }
+        report::step("Overwriting changes from 'draft' which are not applied yet");
+    }
+    repo.set_drafted(false)?;
 
     // Make a list of paths in 'tree' and in git
-    println!("care: Collecting paths in git");
+    report::step("Collecting paths in git");
     let mut paths = PathSet::new();
-    // TODO: unicode normaliz.: https://stackoverflow.com/q/47813162/#comment82595250_47813878
-    let mut case_insensitive_paths = std::collections::HashMap::<UniCase<String>, String>::new();
     repo.walk_paths_pre_order(|slash_path| {
-        if script.ignores_path(&slash_path) {
+        if script.ignores_path(&slash_path) || metadata::is_reserved(&slash_path) {
             return git2::TreeWalkResult::Skip;
         }
-        // TODO: also check if entry already existed here
-        case_insensitive_paths.insert(slash_path.clone().into(), slash_path.clone());
+        if !filter.accepts(&slash_path) {
+            return git2::TreeWalkResult::Ok;
+        }
         paths.insert(slash_path);
         git2::TreeWalkResult::Ok
     })?;
     // for k in &paths {
     //     println!(" - {k:?}");
     // }
-    println!("care: Collecting paths in script");
+    report::step("Collecting paths in script");
     for path in script.paths.keys() {
-        if script.ignores_path(&path) {
-            bail!("Path {path:?} from script matches an ignored prefix");
+        if script.ignores_path(path) {
+            bail!("Path {path:?} from script matches an ignore pattern");
         }
-        let unicase = path.clone().into();
-        if let Some(found) = case_insensitive_paths.get(&unicase) {
-            if found.as_str() != path {
-                bail!("Found casing difference between git path {found:?} and input path {path:?}");
-            }
+        if !filter.accepts(path) {
+            continue;
         }
-        // TODO: case_insensitive_paths.insert(path, path);
         paths.insert(path.clone());
     }
+    collide::ensure_none(script, paths.iter().map(String::as_str))?;
+    // Process paths in the order of effectors declared in the script.
+    let mut paths = Vec::from_iter(paths);
+    paths.sort_by_key(|path| script.effector_rank(path));
     for k in &paths {
         debug!(" - {k:?}");
     }
 
     // Run 'check' on appropriate effectors for all listed paths, fetching files into the git workspace
-    println!("care: Checking:");
+    report::step("Checking:");
     let dir = Dir::open_ambient_dir(&script.shadow_dir, ambient_authority())?;
     for path in &paths {
         if let Some(parent) = parent_dir(&PathBuf::from_slash(path)) {
             dir.create_dir_all(parent).context("in shadow_dir")?;
         }
-        let (prefix, subpath) = split_effector_path(path);
-        println!("care:   {prefix}: {subpath}");
-        let found = effectors.detect(prefix, subpath)?;
-        let shadow_path = PathBuf::from(&script.shadow_dir).join(PathBuf::from_slash(path));
-        if !found {
-            std::fs::remove_file(shadow_path).or_else(ignore_err_not_found)?;
-            continue;
-        }
-        effectors.gather(prefix, subpath, &script.shadow_dir)?;
+        // Gather a recorded path in the same form as recorded, i.e. with the
+        // same metadata keys, even if the script changed them: effectors may
+        // store contents differently depending on the keys, so that only
+        // this way the machine's state can be compared with the record.
+        let keys = match repo.head_blob(path)? {
+            Some(_) => {
+                let recorded_meta = repo.head_blob(&metadata::sidecar_of(path))?;
+                Vec::from_iter(metadata::parse(recorded_meta.as_deref())?.into_keys())
+            }
+            None => script
+                .metadata
+                .get(path)
+                .map(|meta| Vec::from_iter(meta.keys().cloned()))
+                .unwrap_or_default(),
+        };
+        let (prefix, subpath) = split_effector_path(script, path)?;
+        let event = PathEvent {
+            prefix,
+            subpath,
+            action: "gather",
+            ..Default::default()
+        };
+        report::path(event, || {
+            let found = effectors.detect(prefix, subpath)?;
+            let shadow_path = PathBuf::from(&script.shadow_dir).join(PathBuf::from_slash(path));
+            if !found {
+                std::fs::remove_file(shadow_path).or_else(ignore_err_not_found)?;
+                return write_sidecar(&dir, path, &Metadata::new());
+            }
+            let meta = effectors.gather(prefix, subpath, &script.shadow_dir, &keys)?;
+            write_sidecar(&dir, path, &meta)
+        })?;
     }
 
-    // Two-way compare: current git <-> results of effectors.query
-    if !repo.statuses_are_empty(&script.ignores)? {
+    // 3-way compare: curr git <-> effectors.query results <-> parsed input
+    // TODO: https://github.com/akavel/drafts/blob/main/20231122-001-mana2.md
+    report::step("Reconciling:");
+    let mut tally = Tally::new(script.effectors.keys());
+    for path in &paths {
+        let sidecar = metadata::sidecar_of(path);
+        let recorded = (repo.head_blob(path)?, repo.head_blob(&sidecar)?);
+        let actual = (
+            read_if_exists(&dir, path).context("in shadow_dir")?,
+            read_if_exists(&dir, &sidecar).context("in shadow_dir")?,
+        );
+        let desired = (
+            script.paths.get(path).cloned(),
+            script.metadata.get(path).and_then(metadata::render),
+        );
+        let state = PathState::classify(recorded, actual, desired);
+        if state == PathState::Unchanged {
+            debug!(" = {path:?}");
+        } else {
+            report::text(&format!("care:   {state}: {path}"));
+        }
+        let (prefix, subpath) = split_effector_path(script, path)?;
+        report::path_event(&PathEvent {
+            prefix,
+            subpath,
+            action: "reconcile",
+            result: &state.to_string(),
+            ..Default::default()
+        });
+        tally.add(prefix, state);
+    }
+    report::summary(&tally.totals());
+
+    // Converged paths are expected to differ from HEAD, until applied.
+    let clean = repo_has_only_managed_changes(repo, script)?;
+    if tally.has_drift() {
+        report::step(&format!(
+            "Drift detected: real disk contents differ from the last generation; check git diff in shadow repo: {:?}",
+            script.shadow_dir,
+        ));
+    } else if !clean {
         bail!(
-            "real disk contents differ from expected prerequisites; check git diff in shadow repo: {:?}", script.shadow_dir,
+            "git 'shadow_dir' repository changed unexpectedly while checking (see: git status): {:?}",
+            script.shadow_dir,
         );
     }
+    Ok(tally)
+}
 
-    // TODO: 3-way compare: curr git <-> effectors.query results <-> parsed input
-    // TODO: https://github.com/akavel/drafts/blob/main/20231122-001-mana2.md
-    Ok(())
+fn draft(script: Script, filter: &PathFilter) -> Result<()> {
+    let repo = open_repo(&script)?;
+    run_draft(&script, filter, &repo)
 }
 
-fn draft(script: Script) -> Result<()> {
+fn run_draft(script: &Script, filter: &PathFilter, repo: &Repo) -> Result<()> {
     // Make a list of paths in git
-    println!("care: Opening shadow repository");
-    let repo = Repo::open(&script.shadow_dir)?;
-    println!("care: Collecting paths in git");
-    // TODO: unicode normaliz.: https://stackoverflow.com/q/47813162/#comment82595250_47813878
-    //let mut case_insensitive_paths = std::collections::HashMap::<UniCase<String>, String>::new();
+    report::step("Collecting paths in git");
     let mut paths = PathSet::new();
     repo.walk_paths_pre_order(|slash_path| {
-        if script.ignores_path(&slash_path) {
+        if script.ignores_path(&slash_path) || metadata::is_reserved(&slash_path) {
             return git2::TreeWalkResult::Skip;
         }
-        // FIXME: bring back case_insensitive_paths
-        // TODO: also check if entry already existed here
-        //case_insensitive_paths.insert(slash_path.clone().into(), slash_path.clone());
+        if !filter.accepts(&slash_path) {
+            return git2::TreeWalkResult::Ok;
+        }
         paths.insert(slash_path);
         git2::TreeWalkResult::Ok
     })?;
 
     // TODO[LATER]: maybe check if git status clean at script.shadow_dir
 
-    // TODO[LATER]: validate that paths were not already added (and do it case insensitively)
-    // TODO[LATER]: allow case-sensitive check with an explicit CLI flag
+    // Check before writing anything, as clashing paths could overwrite each
+    // other in 'shadow_dir', or on the machine when applied.
+    let script_paths = script.paths.keys().filter(|p| filter.accepts(p));
+    collide::ensure_none(script, paths.iter().chain(script_paths).map(String::as_str))?;
     let dir = Dir::open_ambient_dir(script.shadow_dir.clone(), ambient_authority())?;
-    println!("care: Processing paths in script");
+    // Protect the draft from being overwritten by 'check' until applied.
+    repo.set_drafted(true)?;
+    report::step("Processing paths in script");
     for (path, contents) in &script.paths {
         debug!(" - {path}");
-        if script.ignores_path(&path) {
-            bail!("Path {path:?} from script matches an ignored prefix");
+        if script.ignores_path(path) {
+            bail!("Path {path:?} from script matches an ignore pattern");
+        }
+        if !filter.accepts(path) {
+            continue;
         }
         // TODO[LATER]: try if things will "just work" on Windows without explicit from_slash conversions
-        let os_path = PathBuf::from_slash(&path);
+        let os_path = PathBuf::from_slash(path);
         if let Some(parent) = parent_dir(&os_path) {
             dir.create_dir_all(parent).context("in shadow_dir")?;
         }
-        dir.write(&path, contents).context("in shadow_dir")?;
+        dir.write(path, contents).context("in shadow_dir")?;
+        let meta = script.metadata.get(path).cloned().unwrap_or_default();
+        write_sidecar(&dir, path, &meta)?;
+        report_drafted(script, path, "write")?;
 
         paths.remove(path);
     }
@@ -199,60 +542,442 @@ fn draft(script: Script) -> Result<()> {
     // Delete files found on disk but not found in script
     for path in &paths {
         dir.remove_file(path)?;
+        write_sidecar(&dir, path, &Metadata::new())?;
+        report_drafted(script, path, "delete")?;
     }
 
-    // TODO[LATER]: add support for binary files, maybe somehow
+    Ok(())
+}
 
+/// Writes the metadata sidecar file of `path` in the shadow directory, or
+/// removes it if `meta` is empty.
+fn write_sidecar(dir: &Dir, path: &str, meta: &Metadata) -> Result<()> {
+    let sidecar = metadata::sidecar_of(path);
+    match metadata::render(meta) {
+        Some(content) => {
+            if let Some(parent) = parent_dir(&PathBuf::from_slash(&sidecar)) {
+                dir.create_dir_all(parent).context("in shadow_dir")?;
+            }
+            dir.write(&sidecar, content).context("in shadow_dir")?;
+        }
+        None => dir
+            .remove_file(&sidecar)
+            .or_else(ignore_err_not_found)
+            .context("in shadow_dir")?,
+    }
     Ok(())
 }
 
-fn apply(script: Script) -> Result<()> {
-    println!("care: Opening shadow repository");
-    let repo = Repo::open(&script.shadow_dir)?;
+fn report_drafted(script: &Script, path: &str, action: &str) -> Result<()> {
+    let (prefix, subpath) = split_effector_path(script, path)?;
+    report::path_event(&PathEvent {
+        prefix,
+        subpath,
+        action,
+        result: "ok",
+        ..Default::default()
+    });
+    Ok(())
+}
 
-    // Initialize effectors
-    println!("care: Starting effectors:");
-    let mut effectors = Effectors::init(&script.effectors)?;
+fn apply(
+    script: Script,
+    filter: &PathFilter,
+    plan_file: Option<&Path>,
+    interactive: bool,
+) -> Result<()> {
+    let repo = open_repo(&script)?;
 
     // iterate modified files in repo, incl. untracked
     // TODO: also iterate unmodified?
-    println!("care: Collecting pending paths in git");
+    report::step("Collecting pending paths in git");
+    let pending = care::plan::pending_paths(&repo, &script, filter)?;
+    if let Some(plan_file) = plan_file {
+        report::step("Verifying saved plan");
+        SavedPlan::load(plan_file)?.verify(&pending)?;
+    }
+    let mut effectors = start_effectors(&script)?;
+    apply_pending(&script, &repo, &mut effectors, &pending, interactive, None)
+}
+
+/// Applies `pending` paths to the machine with effectors, adding each one to
+/// git index after success, then records a new generation with `note`. If
+/// `interactive`, asks before applying each path.
+fn apply_pending(
+    script: &Script,
+    repo: &Repo,
+    effectors: &mut Effectors,
+    pending: &[PendingPath],
+    interactive: bool,
+    note: Option<&str>,
+) -> Result<()> {
+    let res = affect_pending(script, repo, effectors, pending, interactive);
+    // Record the paths applied so far also if stopped early, so that the
+    // remaining ones stay pending for the next run.
+    match care::generation::commit(repo, script, note)? {
+        Some(n) => report::step(&format!("Recorded generation {n}")),
+        None => report::step("No changes to record"),
+    }
+    res
+}
+
+/// Applies `pending` paths to the machine with effectors, adding each one to
+/// git index after success. Stops at the first failure, or when asked to quit
+/// if `interactive`.
+fn affect_pending(
+    script: &Script,
+    repo: &Repo,
+    effectors: &mut Effectors,
+    pending: &[PendingPath],
+    interactive: bool,
+) -> Result<()> {
     let mut git_index = repo.index()?;
-    println!("care: Affecting:");
-    for stat in &repo.all_pending()? {
-        let Some(path) = stat.path() else {
-            bail!(
-                "Path from 'git status' cannot be parsed as utf8: {:?}",
-                stat.path()
-            );
+    report::step("Affecting:");
+    let mut skip_all = false;
+    for PendingPath { path, change, .. } in pending {
+        debug!(" * {:?}", path);
+        let os_rel_path = PathBuf::from_slash(path);
+        let (prefix, subpath) = split_effector_path(script, path)?;
+        let event = PathEvent {
+            prefix,
+            subpath,
+            action: "affect",
+            change: Some(*change),
+            ..Default::default()
         };
-        if script.ignores_path(path) {
-            debug!(" ^ {:?}", path);
+        let skip = match (skip_all, interactive) {
+            (true, _) => true,
+            (false, false) => false,
+            (false, true) => {
+                println!("care:   {prefix}: {subpath} ({change})");
+                print!("{}", care::plan::pending_diff(repo, path)?);
+                let answers = ["yes", "no", "skip all", "quit"];
+                match answers[ask("care: Apply this change?", &answers)?] {
+                    "yes" => false,
+                    "no" => true,
+                    "skip all" => {
+                        skip_all = true;
+                        true
+                    }
+                    _ => {
+                        report::step("Quitting; remaining paths are left pending");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        if skip {
+            report::text(&format!("care:   {prefix}: {subpath} (skipped)"));
+            report::path_event(&PathEvent {
+                result: "skipped",
+                ..event
+            });
             continue;
         }
-        debug!(" * {:?}", path);
-        let os_rel_path = PathBuf::from_slash(path);
-        let (prefix, subpath) = split_effector_path(path);
-        println!("care:   {prefix}: {subpath}");
-        effectors.affect(prefix, subpath, &script.shadow_dir)?;
-        use git2::Status;
-        match stat.status() {
-            Status::WT_NEW | Status::WT_MODIFIED => {
+        let sidecar = PathBuf::from_slash(metadata::sidecar_of(path));
+        let shadow_sidecar = script.shadow_dir.join(&sidecar);
+        let meta = std::fs::read(&shadow_sidecar)
+            .map(Some)
+            .or_else(ignore_err_not_found)?;
+        let meta = metadata::parse(meta.as_deref())?;
+        report::path(event, || {
+            effectors.affect(prefix, subpath, &script.shadow_dir, &meta)
+        })?;
+        match change {
+            Change::New | Change::Modified => {
                 git_index.add_path(&os_rel_path)?;
             }
-            Status::WT_DELETED => {
+            Change::Deleted => {
                 git_index.remove_path(&os_rel_path)?;
             }
-            s => {
-                bail!("unsupported git status {s:?} for path {path:?} in 'shadow_dir'");
-            }
+        }
+        if shadow_sidecar.exists() {
+            git_index.add_path(&sidecar)?;
+        } else {
+            git_index.remove_path(&sidecar)?;
         }
         git_index.write()?;
     }
+    Ok(())
+}
 
+fn rollback(script: Script, filter: &PathFilter, generation: u64) -> Result<()> {
+    let repo = open_repo(&script)?;
+    if !repo_is_clean(&repo, &script)? {
+        bail!("git 'shadow_dir' repository is not clean (see: git status)");
+    }
+
+    report::step(&format!(
+        "Restoring generation {generation} in shadow repository"
+    ));
+    let Some(commit) = care::generation::find(&repo, generation)? else {
+        bail!("generation {generation} not found in history of 'shadow_dir' repository");
+    };
+    repo.checkout_to_workdir(commit)?;
+
+    report::step("Collecting pending paths in git");
+    let pending = care::plan::pending_paths(&repo, &script, filter)?;
+    let note = format!("rollback to generation {generation}");
+    let mut effectors = start_effectors(&script)?;
+    apply_pending(&script, &repo, &mut effectors, &pending, false, Some(&note))
+}
+
+fn plan(script: Script, filter: &PathFilter, output: Option<&Path>) -> Result<()> {
+    let repo = open_repo(&script)?;
+
+    report::step("Collecting pending paths in git");
+    let pending = care::plan::pending_paths(&repo, &script, filter)?;
+    if let Some(output) = output {
+        report::step(&format!("Saving plan to {output:?}"));
+        SavedPlan {
+            paths: pending.clone(),
+        }
+        .save(output)?;
+    }
+    print_plan(&script, &repo, &pending)
+}
+
+fn print_plan(script: &Script, repo: &Repo, pending: &[PendingPath]) -> Result<()> {
+    if pending.is_empty() {
+        report::step("Nothing to apply");
+        return Ok(());
+    }
+    // Paths are already in the order of effectors, so grouping consecutive
+    // ones is enough.
+    let split = pending
+        .iter()
+        .map(|p| Ok((split_effector_path(script, &p.path)?, p)))
+        .collect::<Result<Vec<_>>>()?;
+    let by_prefix = split.into_iter().chunk_by(|((prefix, _), _)| *prefix);
+    report::step("Would affect:");
+    for (prefix, paths) in &by_prefix {
+        report::text(&format!("care:   {prefix}:"));
+        for ((_, subpath), PendingPath { path, change, .. }) in paths {
+            let diff = care::plan::pending_diff(repo, path)?;
+            report::text(&format!("care:     {change}: {subpath}"));
+            report::text(diff.trim_end_matches('\n'));
+            report::path_event(&PathEvent {
+                prefix,
+                subpath,
+                action: "plan",
+                change: Some(*change),
+                result: "pending",
+                diff: Some(&diff),
+                ..Default::default()
+            });
+        }
+    }
     Ok(())
 }
 
+fn sync(script: Script, filter: &PathFilter, confirm: bool) -> Result<()> {
+    let repo = open_repo(&script)?;
+    let mut effectors = start_effectors(&script)?;
+
+    report::text("care: Phase: check");
+    report::phase("check", || {
+        let tally = run_check(&script, filter, &repo, &mut effectors, false)?;
+        if tally.has_drift() {
+            bail!("real disk contents differ from the last generation");
+        }
+        Ok(())
+    })
+    .context("sync stopped in phase 'check'")?;
+
+    report::text("care: Phase: draft");
+    report::phase("draft", || run_draft(&script, filter, &repo))
+        .context("sync stopped in phase 'draft'")?;
+
+    report::text("care: Phase: apply");
+    report::phase("apply", || {
+        let pending = care::plan::pending_paths(&repo, &script, filter)?;
+        if pending.is_empty() {
+            report::step("Nothing to apply");
+            return Ok(());
+        }
+        if confirm {
+            print_plan(&script, &repo, &pending)?;
+            if !ask_yes_no("care: Apply the above changes?")? {
+                report::step("Not applying; changes stay pending in 'shadow_dir'");
+                return Ok(());
+            }
+        }
+        apply_pending(&script, &repo, &mut effectors, &pending, false, None)
+    })
+    .context("sync stopped in phase 'apply'")
+}
+
+fn adopt(script: Script, paths: &[String], output: Option<&Path>) -> Result<()> {
+    for path in paths {
+        if script.split_path(path).is_none() {
+            bail!("path {path:?} must start with a prefix of an effector, like: prefix/subpath");
+        }
+        if script.paths.contains_key(path) {
+            bail!("path {path:?} is already in the script");
+        }
+        if script.ignores_path(path) {
+            bail!("path {path:?} matches an ignore pattern");
+        }
+    }
+    let mut effectors = start_effectors(&script)?;
+
+    // Gather into a temporary directory, so that the shadow repository
+    // stays untouched.
+    report::step("Gathering:");
+    let tmp = tempfile::tempdir()?;
+    let mut adopted = script::PathContentMap::new();
+    for path in paths {
+        let (prefix, subpath) = split_effector_path(&script, path)?;
+        let event = PathEvent {
+            prefix,
+            subpath,
+            action: "gather",
+            ..Default::default()
+        };
+        let content = report::path(event, || {
+            if !effectors.detect(prefix, subpath)? {
+                bail!("path {path:?} not found on the machine");
+            }
+            let tmp_path = tmp.path().join(PathBuf::from_slash(path));
+            std::fs::create_dir_all(tmp_path.parent().unwrap())?;
+            effectors.gather(prefix, subpath, tmp.path(), &[])?;
+            Ok(std::fs::read(&tmp_path)?)
+        })?;
+        adopted.insert(path.clone(), content);
+    }
+
+    let snippet = ncl::tree_snippet(&script::machine_field()?, &adopted);
+    match output {
+        Some(output) => {
+            report::step(&format!("Writing Nickel record to {output:?}"));
+            std::fs::write(output, snippet).with_context(|| format!("writing {output:?}"))?;
+        }
+        None => print!("{snippet}"),
+    }
+    Ok(())
+}
+
+fn forget(script: Script, paths: &[String]) -> Result<()> {
+    let repo = open_repo(&script)?;
+    for path in paths {
+        if script.paths.contains_key(path) {
+            bail!("path {path:?} is still in the script; remove it from 'tree' first");
+        }
+        if repo.head_blob(path)?.is_none() {
+            bail!("path {path:?} is not managed: not found in the last generation");
+        }
+    }
+    // The whole index gets committed, so it must not carry other changes.
+    if !repo.index_matches_head()? {
+        bail!("git 'shadow_dir' repository has staged changes (see: git status)");
+    }
+
+    // Only the shadow repository is changed; effectors are not involved, so
+    // the paths are left as they are on the machine.
+    report::step("Forgetting:");
+    let mut git_index = repo.index()?;
+    for path in paths {
+        let (prefix, subpath) = split_effector_path(&script, path)?;
+        let event = PathEvent {
+            prefix,
+            subpath,
+            action: "forget",
+            ..Default::default()
+        };
+        report::path(event, || {
+            for slash_path in [path.clone(), metadata::sidecar_of(path)] {
+                let os_rel_path = PathBuf::from_slash(slash_path);
+                git_index.remove_path(&os_rel_path)?;
+                let shadow_path = script.shadow_dir.join(&os_rel_path);
+                std::fs::remove_file(shadow_path).or_else(ignore_err_not_found)?;
+            }
+            Ok(())
+        })?;
+    }
+    git_index.write()?;
+
+    let note = format!("forget {}", paths.join(", "));
+    match care::generation::commit(&repo, &script, Some(&note))? {
+        Some(n) => report::step(&format!("Recorded generation {n}")),
+        None => report::step("No changes to record"),
+    }
+    Ok(())
+}
+
+fn open_repo(script: &Script) -> Result<Repo> {
+    report::step("Opening shadow repository");
+    Repo::open(&script.shadow_dir)
+}
+
+/// Checks that the shadow repository has no changes, other than in paths
+/// ignored by the script, including their metadata.
+fn repo_is_clean(repo: &Repo, script: &Script) -> Result<bool> {
+    repo.statuses_are_empty(|p| script.ignores_path(metadata::path_of(p).unwrap_or(p)))
+}
+
+/// Like [`repo_is_clean`], but also allows unstaged changes of paths which
+/// are recorded in HEAD or present in the script, including their sidecars.
+fn repo_has_only_managed_changes(repo: &Repo, script: &Script) -> Result<bool> {
+    use git2::Status;
+    let staged = Status::INDEX_NEW
+        | Status::INDEX_MODIFIED
+        | Status::INDEX_DELETED
+        | Status::INDEX_RENAMED
+        | Status::INDEX_TYPECHANGE
+        | Status::CONFLICTED;
+    for entry in repo.all_pending()?.iter() {
+        // Paths which are not valid UTF-8 cannot be managed.
+        let Some(path) = entry.path() else {
+            return Ok(false);
+        };
+        let path = metadata::path_of(path).unwrap_or(path);
+        if script.ignores_path(path) {
+            continue;
+        }
+        if entry.status().intersects(staged) {
+            return Ok(false);
+        }
+        if !script.paths.contains_key(path) && repo.head_blob(path)?.is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn start_effectors(script: &Script) -> Result<Effectors> {
+    report::step("Starting effectors:");
+    Effectors::init(&script.effectors)
+}
+
+fn ask_yes_no(question: &str) -> Result<bool> {
+    Ok(ask(question, &["yes", "no"])? == 0)
+}
+
+/// Asks a question on stdout, and waits for one of the `answers` (or its
+/// first letter) on stdin. Returns the index of the answer.
+fn ask(question: &str, answers: &[&str]) -> Result<usize> {
+    use std::io::Write as _;
+    let hint = answers
+        .iter()
+        .map(|a| format!("[{}]{}", &a[..1], &a[1..]))
+        .join("/");
+    loop {
+        print!("{question} {hint} ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            bail!("no answer, got EOF on stdin");
+        }
+        let answer = answer.trim();
+        let found = answers
+            .iter()
+            .position(|a| answer == *a || answer == &a[..1]);
+        if let Some(i) = found {
+            return Ok(i);
+        }
+    }
+}
+
 type PathSet = BTreeSet<String>;
 
 fn parent_dir(path: &Path) -> Option<&Path> {
@@ -260,13 +985,15 @@ fn parent_dir(path: &Path) -> Option<&Path> {
     path.parent().filter(|p| *p != Path::new(""))
 }
 
-fn split_effector_path(path: &str) -> (&str, &str) {
-    let Some(idx) = path.find('/') else {
-        panic!("slash not found in path: {path:?}");
+fn split_effector_path<'a>(script: &Script, path: &'a str) -> Result<(&'a str, &'a str)> {
+    let Some(split) = script.split_path(path) else {
+        bail!("no effector for path {path:?}");
     };
-    let (start, rest) = path.split_at(idx);
-    let (_slash, end) = rest.split_at(1);
-    (start, end)
+    Ok(split)
+}
+
+fn read_if_exists(dir: &Dir, path: &str) -> std::io::Result<Option<Vec<u8>>> {
+    dir.read(path).map(Some).or_else(ignore_err_not_found)
 }
 
 fn ignore_err_not_found<T: Default>(err: std::io::Error) -> Result<T, std::io::Error> {
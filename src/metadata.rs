@@ -0,0 +1,73 @@
+use anyhow::{bail, Result};
+use effectors::Metadata;
+
+pub use script::RESERVED_PREFIX as RESERVED;
+
+/// Directory in the shadow repository holding metadata of paths, like file
+/// mode or owner. Metadata is stored in sidecar files at the same relative
+/// paths as the paths they describe, so that git tracks its changes like any
+/// content. A sidecar file holds one `key=value` line per entry.
+pub const DIR: &str = ".care/meta";
+
+pub fn is_reserved(path: &str) -> bool {
+    path.split('/').next() == Some(RESERVED)
+}
+
+/// Returns the path of the sidecar file holding metadata of `path`.
+pub fn sidecar_of(path: &str) -> String {
+    format!("{DIR}/{path}")
+}
+
+/// Returns the path described by a sidecar file, if `sidecar` is one.
+pub fn path_of(sidecar: &str) -> Option<&str> {
+    sidecar.strip_prefix(DIR)?.strip_prefix('/')
+}
+
+/// Renders contents of a sidecar file, or `None` if `meta` is empty and no
+/// sidecar file is needed.
+pub fn render(meta: &Metadata) -> Option<Vec<u8>> {
+    if meta.is_empty() {
+        return None;
+    }
+    let lines = meta.iter().map(|(k, v)| format!("{k}={v}\n"));
+    Some(lines.collect::<String>().into_bytes())
+}
+
+/// Parses contents of a sidecar file, treating a missing file as no metadata.
+pub fn parse(sidecar: Option<&[u8]>) -> Result<Metadata> {
+    let mut meta = Metadata::new();
+    let Some(sidecar) = sidecar else {
+        return Ok(meta);
+    };
+    for line in std::str::from_utf8(sidecar)?.lines() {
+        let Some((k, v)) = line.split_once('=') else {
+            bail!("expected metadata line as key=value, got: {line:?}");
+        };
+        meta.insert(k.to_string(), v.to_string());
+    }
+    Ok(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_roundtrip() {
+        assert_eq!(sidecar_of("home/.bashrc"), ".care/meta/home/.bashrc");
+        assert_eq!(path_of(".care/meta/home/.bashrc"), Some("home/.bashrc"));
+        assert_eq!(path_of(".care/metadata"), None);
+        assert!(is_reserved(".care/meta/x") && !is_reserved(".cares/x"));
+
+        let meta = Metadata::from([
+            ("mode".to_string(), "0755".to_string()),
+            ("owner".to_string(), "root".to_string()),
+        ]);
+        let rendered = render(&meta).unwrap();
+        assert_eq!(rendered, b"mode=0755\nowner=root\n");
+        assert_eq!(parse(Some(&rendered)).unwrap(), meta);
+        assert_eq!(render(&Metadata::new()), None);
+        assert_eq!(parse(None).unwrap(), Metadata::new());
+        assert!(parse(Some(b"mode")).is_err());
+    }
+}
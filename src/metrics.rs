@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::reconcile::Tally;
+
+/// Renders results of a check in the Prometheus text exposition format, for
+/// use with a textfile collector. A `None` tally means the check failed.
+pub fn render(tally: Option<&Tally>, time: SystemTime) -> String {
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, samples: &[(String, usize)]| {
+        writeln!(out, "# HELP {name} {help}").expect("write to String");
+        writeln!(out, "# TYPE {name} gauge").expect("write to String");
+        for (labels, value) in samples {
+            writeln!(out, "{name}{labels} {value}").expect("write to String");
+        }
+    };
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    gauge(
+        "care_check_timestamp_seconds",
+        "Time when care check last ran, in seconds since the Unix epoch.",
+        &[(String::new(), seconds as usize)],
+    );
+    gauge(
+        "care_check_success",
+        "Whether care check last finished without an error.",
+        &[(String::new(), tally.is_some() as usize)],
+    );
+    let Some(tally) = tally else {
+        return out;
+    };
+    gauge(
+        "care_check_drift",
+        "Whether care check last found any drifted paths.",
+        &[(String::new(), tally.has_drift() as usize)],
+    );
+    let drifted = tally
+        .counts
+        .keys()
+        .map(|prefix| (labels(&[("prefix", prefix)]), tally.drifted(prefix)))
+        .collect::<Vec<_>>();
+    gauge(
+        "care_drifted_paths",
+        "Number of paths which drifted away from the last generation, per effector prefix.",
+        &drifted,
+    );
+    let checked = tally
+        .counts
+        .iter()
+        .flat_map(|(prefix, states)| {
+            states.iter().map(move |(state, n)| {
+                let state = state.to_string();
+                (labels(&[("prefix", prefix), ("state", &state)]), *n)
+            })
+        })
+        .collect::<Vec<_>>();
+    gauge(
+        "care_checked_paths",
+        "Number of checked paths, per effector prefix and state.",
+        &checked,
+    );
+    out
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs = pairs.iter().map(|(name, value)| {
+        let value = value
+            .replace('\\', r"\\")
+            .replace('"', r#"\""#)
+            .replace('\n', r"\n");
+        format!("{name}=\"{value}\"")
+    });
+    format!("{{{}}}", pairs.collect::<Vec<_>>().join(","))
+}
+
+/// Writes `contents` to `path` atomically, so that a textfile collector never
+/// sees a partially written file.
+#[context("writing metrics to {path:?}")]
+pub fn write_textfile(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path).context("renaming temporary file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconcile::PathState;
+    use std::time::Duration;
+
+    #[test]
+    fn render_drift_per_prefix() {
+        let prefixes = ["apps".to_string(), "home".to_string()];
+        let mut tally = Tally::new(&prefixes);
+        tally.add("home", PathState::Unchanged);
+        tally.add("home", PathState::Drifted);
+        tally.add("home", PathState::Conflict);
+        let time = UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let text = render(Some(&tally), time);
+        assert!(text.contains("\ncare_check_timestamp_seconds 1700000000\n"));
+        assert!(text.contains("\ncare_check_success 1\n"));
+        assert!(text.contains("\ncare_check_drift 1\n"));
+        assert!(text.contains("\ncare_drifted_paths{prefix=\"apps\"} 0\n"));
+        assert!(text.contains("\ncare_drifted_paths{prefix=\"home\"} 2\n"));
+        assert!(text.contains("\ncare_checked_paths{prefix=\"home\",state=\"unchanged\"} 1\n"));
+
+        let text = render(None, time);
+        assert!(text.contains("\ncare_check_success 0\n"));
+        assert!(!text.contains("care_drifted_paths"));
+    }
+}
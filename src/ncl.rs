@@ -0,0 +1,75 @@
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+/// Renders `s` as a Nickel string literal.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => quoted.push_str(r"\\"),
+            '"' => quoted.push_str(r#"\""#),
+            '\n' => quoted.push_str(r"\n"),
+            '\r' => quoted.push_str(r"\r"),
+            '\t' => quoted.push_str(r"\t"),
+            // Escape the start of an interpolation.
+            '%' if chars.peek() == Some(&'{') => quoted.push_str(r"\%"),
+            c if c.is_ascii_control() => {
+                write!(quoted, r"\x{:02x}", c as u8).expect("write to String")
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Renders a Nickel record defining `tree` entries with given contents for
+/// the given machine. The record can be merged into a script, with `&`.
+/// Contents which are not valid UTF-8 are rendered as base64 leaf records.
+pub fn tree_snippet(machine: &str, paths: &BTreeMap<String, Vec<u8>>) -> String {
+    let mut out = "{\n".to_string();
+    for (path, content) in paths {
+        let fields = path.split('/').map(quote).collect::<Vec<_>>().join(".");
+        let value = match std::str::from_utf8(content) {
+            Ok(text) => quote(text),
+            Err(_) => format!(
+                r#"{{ ".care" = "leaf", base64 = {} }}"#,
+                quote(&BASE64_STANDARD.encode(content))
+            ),
+        };
+        writeln!(out, "  {}.tree.{fields} = {value},", quote(machine)).expect("write to String");
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_and_snippet() {
+        assert_eq!(quote("a b"), r#""a b""#);
+        assert_eq!(quote("\"\\%{x}%"), r#""\"\\\%{x}%""#);
+        assert_eq!(quote("1\n\t2\r\x1b"), r#""1\n\t2\r\x1b""#);
+
+        let paths = BTreeMap::from([
+            ("home/.bashrc".to_string(), b"ls\n".to_vec()),
+            ("home/icon".to_string(), vec![0, 1, 2, 255]),
+            ("home/my dir/x".to_string(), vec![]),
+        ]);
+        assert_eq!(
+            tree_snippet("me@pc", &paths),
+            r#"{
+  "me@pc".tree."home".".bashrc" = "ls\n",
+  "me@pc".tree."home"."icon" = { ".care" = "leaf", base64 = "AAEC/w==" },
+  "me@pc".tree."home"."my dir"."x" = "",
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use git2::Status;
+use script::{collide, Script};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::filter::PathFilter;
+use crate::metadata;
+use crate::repo::Repo;
+
+/// Kind of change pending in the shadow repository's working directory for
+/// a single path, i.e. what `apply` will do to the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    New,
+    Modified,
+    Deleted,
+}
+
+impl Change {
+    fn of_status(status: Status) -> Option<Self> {
+        match status {
+            Status::WT_NEW => Some(Change::New),
+            Status::WT_MODIFIED => Some(Change::Modified),
+            Status::WT_DELETED => Some(Change::Deleted),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Change::New => "new",
+            Change::Modified => "modified",
+            Change::Deleted => "deleted",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingPath {
+    pub path: String,
+    pub change: Change,
+    /// Git blob hash of the path's contents in the shadow working directory,
+    /// `None` if the path is being deleted.
+    pub blob: Option<String>,
+    /// Git blob hash of the path's metadata sidecar file in the shadow working
+    /// directory, `None` if the path has no metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta_blob: Option<String>,
+}
+
+/// Lists paths with changes pending in the shadow repository, skipping the
+/// ones ignored by the script or not accepted by `filter`, in the order of
+/// effectors declared in the script. Fails if any status cannot be applied,
+/// or any paths would clash on the machine, before anything is done to it.
+pub fn pending_paths(
+    repo: &Repo,
+    script: &Script,
+    filter: &PathFilter,
+) -> Result<Vec<PendingPath>> {
+    // Changes of metadata sidecar files are reported for the paths they
+    // describe, as modifications, unless the path itself changed too.
+    let mut changes = BTreeMap::<String, Option<Change>>::new();
+    for stat in &repo.all_pending()? {
+        let Some(stat_path) = stat.path() else {
+            bail!(
+                "Path from 'git status' cannot be parsed as utf8: {:?}",
+                stat.path_bytes()
+            );
+        };
+        let (path, is_sidecar) = match metadata::path_of(stat_path) {
+            Some(path) => (path, true),
+            None => (stat_path, false),
+        };
+        if script.ignores_path(path) || !filter.accepts(path) {
+            continue;
+        }
+        let Some(change) = Change::of_status(stat.status()) else {
+            bail!(
+                "unsupported git status {:?} for path {stat_path:?} in 'shadow_dir'",
+                stat.status()
+            );
+        };
+        if metadata::is_reserved(path) && !is_sidecar {
+            bail!("unexpected path {path:?} in 'shadow_dir'");
+        }
+        let entry = changes.entry(path.to_string()).or_default();
+        if !is_sidecar {
+            *entry = Some(change);
+        }
+    }
+    let mut pending = Vec::new();
+    for (path, change) in changes {
+        let blob = repo.workdir_blob_id(&path)?.map(|oid| oid.to_string());
+        let meta_blob = repo.workdir_blob_id(&metadata::sidecar_of(&path))?;
+        pending.push(PendingPath {
+            change: change.unwrap_or(Change::Modified),
+            blob,
+            meta_blob: meta_blob.map(|oid| oid.to_string()),
+            path,
+        });
+    }
+    // Applying one of clashing paths could overwrite or delete the other on
+    // the machine, e.g. when renaming `x` to `X`.
+    let mut managed = Vec::new();
+    repo.walk_paths_pre_order(|path| {
+        if !script.ignores_path(&path) && !metadata::is_reserved(&path) {
+            managed.push(path);
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    let pending_paths = pending.iter().map(|p| &p.path);
+    collide::ensure_none(
+        script,
+        managed.iter().chain(pending_paths).map(String::as_str),
+    )?;
+
+    pending.sort_by_key(|p| script.effector_rank(&p.path));
+    Ok(pending)
+}
+
+/// Returns a diff of changes pending in the shadow repository for a path,
+/// including its metadata.
+pub fn pending_diff(repo: &Repo, path: &str) -> Result<String> {
+    Ok(repo.pending_diff(path)? + &repo.pending_diff(&metadata::sidecar_of(path))?)
+}
+
+/// A list of pending paths saved to a file after review, so that `apply` can
+/// later verify it executes exactly what was reviewed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SavedPlan {
+    pub paths: Vec<PendingPath>,
+}
+
+impl SavedPlan {
+    #[context("saving plan to {path:?}")]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json + "\n")?;
+        Ok(())
+    }
+
+    #[context("loading plan from {path:?}")]
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json).context("parsing plan JSON")
+    }
+
+    /// Fails with a list of differences if `pending` paths are not exactly
+    /// the ones recorded in the plan.
+    pub fn verify(&self, pending: &[PendingPath]) -> Result<()> {
+        let by_path = |paths: &'_ [PendingPath]| -> BTreeMap<String, PendingPath> {
+            paths.iter().map(|p| (p.path.clone(), p.clone())).collect()
+        };
+        let (mut planned, actual) = (by_path(&self.paths), by_path(pending));
+        let mut problems = Vec::new();
+        for (path, p) in actual {
+            match planned.remove(&path) {
+                None => problems.push(format!("{path:?}: not in plan")),
+                Some(q) if q != p => problems.push(format!(
+                    "{path:?}: planned {} {}, found {} {}",
+                    q.change,
+                    q.blob.as_deref().unwrap_or("-"),
+                    p.change,
+                    p.blob.as_deref().unwrap_or("-"),
+                )),
+                Some(_) => {}
+            }
+        }
+        for path in planned.keys() {
+            problems.push(format!("{path:?}: planned, but no longer pending"));
+        }
+        if !problems.is_empty() {
+            bail!(
+                "shadow_dir does not match the saved plan:\n  {}",
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pp(path: &str, change: Change, blob: Option<&str>) -> PendingPath {
+        PendingPath {
+            path: path.to_string(),
+            change,
+            blob: blob.map(str::to_string),
+            meta_blob: None,
+        }
+    }
+
+    #[test]
+    fn verify_saved_plan() {
+        let plan = SavedPlan {
+            paths: vec![
+                pp("a/x", Change::New, Some("1")),
+                pp("a/y", Change::Deleted, None),
+            ],
+        };
+        assert!(plan.verify(&plan.paths).is_ok());
+        assert!(plan
+            .verify(&[
+                pp("a/x", Change::New, Some("2")),
+                pp("a/y", Change::Deleted, None)
+            ])
+            .is_err());
+        assert!(plan.verify(&[pp("a/x", Change::New, Some("1"))]).is_err());
+        assert!(plan
+            .verify(&[
+                plan.paths[0].clone(),
+                plan.paths[1].clone(),
+                pp("a/z", Change::Modified, Some("3"))
+            ])
+            .is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Result of a three-way comparison of contents of a single path, between:
+/// the state recorded in the HEAD commit of the shadow repository, the actual
+/// state gathered from the machine, and the desired state from the script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PathState {
+    /// Both the machine and the script agree with the recorded state.
+    Unchanged,
+    /// The machine moved away from the recorded state, the script did not.
+    Drifted,
+    /// The script moved away from the recorded state, the machine did not.
+    ScriptChanged,
+    /// Both the machine and the script moved away from the recorded state,
+    /// but to the same new state.
+    Converged,
+    /// Both the machine and the script moved away from the recorded state,
+    /// each to a different new state.
+    Conflict,
+}
+
+impl PathState {
+    /// Classifies a path given its contents in each of the three states, where
+    /// `None` means the path is absent in a given state. The contents can be
+    /// of any comparable type, e.g. also including the path's metadata.
+    pub fn classify<T: PartialEq>(recorded: T, actual: T, desired: T) -> Self {
+        match (actual == recorded, desired == recorded) {
+            (true, true) => PathState::Unchanged,
+            (false, true) => PathState::Drifted,
+            (true, false) => PathState::ScriptChanged,
+            (false, false) if actual == desired => PathState::Converged,
+            (false, false) => PathState::Conflict,
+        }
+    }
+
+    /// Whether the machine moved away from the recorded state, other than
+    /// to the desired state.
+    pub fn is_drift(self) -> bool {
+        matches!(self, PathState::Drifted | PathState::Conflict)
+    }
+}
+
+impl fmt::Display for PathState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PathState::Unchanged => "unchanged",
+            PathState::Drifted => "drifted",
+            PathState::ScriptChanged => "script-changed",
+            PathState::Converged => "converged",
+            PathState::Conflict => "conflict",
+        })
+    }
+}
+
+/// Numbers of checked paths in each state, per effector prefix.
+#[derive(Debug, Default)]
+pub struct Tally {
+    pub counts: BTreeMap<String, BTreeMap<PathState, usize>>,
+}
+
+impl Tally {
+    /// Creates an empty tally, listing the given prefixes even if they end
+    /// up with no paths.
+    pub fn new<'a>(prefixes: impl IntoIterator<Item = &'a String>) -> Self {
+        let counts = prefixes.into_iter().map(|p| (p.clone(), BTreeMap::new()));
+        Self {
+            counts: counts.collect(),
+        }
+    }
+
+    pub fn add(&mut self, prefix: &str, state: PathState) {
+        let states = self.counts.entry(prefix.to_string()).or_default();
+        *states.entry(state).or_default() += 1;
+    }
+
+    /// Returns the number of drifted paths with the given prefix.
+    pub fn drifted(&self, prefix: &str) -> usize {
+        let Some(states) = self.counts.get(prefix) else {
+            return 0;
+        };
+        states
+            .iter()
+            .filter(|(s, _)| s.is_drift())
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    pub fn has_drift(&self) -> bool {
+        self.counts.keys().any(|prefix| self.drifted(prefix) > 0)
+    }
+
+    /// Returns numbers of paths in each state, summed over all prefixes.
+    pub fn totals(&self) -> BTreeMap<String, usize> {
+        let mut totals = BTreeMap::new();
+        for (state, n) in self.counts.values().flatten() {
+            *totals.entry(state.to_string()).or_default() += n;
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_three_way() {
+        let (a, b, c) = (Some(&b"a"[..]), Some(&b"b"[..]), Some(&b"c"[..]));
+        use PathState::*;
+        assert_eq!(PathState::classify(a, a, a), Unchanged);
+        assert_eq!(PathState::classify(None::<&[u8]>, None, None), Unchanged);
+        assert_eq!(PathState::classify(a, b, a), Drifted);
+        assert_eq!(PathState::classify(a, None, a), Drifted);
+        assert_eq!(PathState::classify(a, a, b), ScriptChanged);
+        assert_eq!(PathState::classify(None, None, a), ScriptChanged);
+        assert_eq!(PathState::classify(a, b, c), Conflict);
+        assert_eq!(PathState::classify(a, b, b), Converged);
+        assert_eq!(PathState::classify(a, None, None), Converged);
+        assert!(!Converged.is_drift());
+        assert_eq!(PathState::classify(None, a, b), Conflict);
+    }
+}
@@ -1,8 +1,12 @@
 use anyhow::{bail, Result};
 use fn_error_context::context;
 use git2::Repository as GitRepo;
+use path_slash::PathBufExt as _;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// Name of the file in the git directory marking an unapplied draft.
+const DRAFT_MARKER: &str = "CARE_DRAFT";
 
 pub struct Repo {
     repo: GitRepo,
@@ -22,19 +26,43 @@ impl Repo {
         Ok(Self { repo })
     }
 
+    /// Creates a new git repository in `dir`, with an initial empty commit,
+    /// so that there's a HEAD to compare against.
+    #[context("initializing git repository {dir:?}")]
+    pub fn init(dir: &Path) -> Result<Repo> {
+        if GitRepo::open(dir).is_ok() {
+            bail!("repository already exists");
+        }
+        let repo = GitRepo::init(dir)?;
+        {
+            let empty_tree = repo.find_tree(repo.index()?.write_tree()?)?;
+            let sig = signature(&repo)?;
+            repo.commit(Some("HEAD"), &sig, &sig, "care: init", &empty_tree, &[])?;
+        }
+        Ok(Self { repo })
+    }
+
     pub fn index(&self) -> Result<git2::Index, git2::Error> {
         self.repo.index()
     }
 
+    /// Checks that the index has no changes staged on top of HEAD.
+    #[context("checking index in git repository")]
+    pub fn index_matches_head(&self) -> Result<bool> {
+        let mut index = self.repo.index()?;
+        Ok(match self.head_tree()? {
+            Some(head_tree) => index.write_tree()? == head_tree.id(),
+            None => index.is_empty(),
+        })
+    }
+
+    /// Checks that there are no changes in the repository, other than in
+    /// paths for which `ignored` returns true.
     #[context("checking statuses in git repository")]
-    pub fn statuses_are_empty(&self, ignores: impl IntoIterator<Item: AsRef<str>>) -> Result<bool> {
-        let mut stat_opt = git2::StatusOptions::new();
-        stat_opt.include_untracked(true);
-        for ign in ignores {
-            stat_opt.pathspec("/".to_owned() + ign.as_ref());
-        }
-        let stat = self.repo.statuses(Some(&mut stat_opt))?;
-        Ok(stat.is_empty())
+    pub fn statuses_are_empty(&self, ignored: impl Fn(&str) -> bool) -> Result<bool> {
+        let stat = self.all_pending()?;
+        // Paths which are not valid UTF-8 cannot be ignored.
+        Ok(stat.iter().all(|s| s.path().is_some_and(&ignored)))
     }
 
     pub fn all_pending(&self) -> Result<git2::Statuses<'_>, git2::Error> {
@@ -44,13 +72,147 @@ impl Repo {
         self.repo.statuses(Some(&mut stat_opt))
     }
 
+    /// Commits the current index on top of HEAD, unless its tree is the same
+    /// as HEAD's. Returns the ID of the new commit, if any.
+    #[context("committing in git repository")]
+    pub fn commit_index(&self, message: &str) -> Result<Option<git2::Oid>> {
+        let tree = self.repo.find_tree(self.repo.index()?.write_tree()?)?;
+        let parent = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(err) if err.code() == git2::ErrorCode::UnbornBranch => None,
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(parent) = &parent {
+            if parent.tree_id() == tree.id() {
+                return Ok(None);
+            }
+        }
+        let sig = signature(&self.repo)?;
+        let parents: Vec<_> = parent.iter().collect();
+        let oid = self
+            .repo
+            .commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)?;
+        Ok(Some(oid))
+    }
+
+    /// Overwrites the working directory with the tree of commit `oid`, leaving
+    /// HEAD and the index unchanged, so that differences show up as pending.
+    #[context("checking out {oid} to git working directory")]
+    pub fn checkout_to_workdir(&self, oid: git2::Oid) -> Result<()> {
+        let commit = self.repo.find_commit(oid)?;
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().update_index(false);
+        self.repo
+            .checkout_tree(commit.as_object(), Some(&mut checkout))?;
+        Ok(())
+    }
+
+    /// Marks whether the working directory holds changes written by `draft`,
+    /// which are not yet applied, with a file in the git directory, like
+    /// git's own `MERGE_HEAD`.
+    #[context("marking draft in git repository")]
+    pub fn set_drafted(&self, drafted: bool) -> Result<()> {
+        let marker = self.repo.path().join(DRAFT_MARKER);
+        if drafted {
+            std::fs::write(marker, "")?;
+        } else if let Err(err) = std::fs::remove_file(marker) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if the working directory was marked with [`Self::set_drafted`].
+    pub fn is_drafted(&self) -> bool {
+        self.repo.path().join(DRAFT_MARKER).exists()
+    }
+
+    /// Walks the first-parent history from HEAD, and returns the ID and
+    /// message of the first commit for which `pred` returns true.
+    pub fn find_in_history<P>(&self, mut pred: P) -> Result<Option<(git2::Oid, String)>>
+    where
+        P: FnMut(&str) -> bool,
+    {
+        if self.head_tree()?.is_none() {
+            return Ok(None);
+        }
+        let mut walk = self.repo.revwalk()?;
+        walk.push_head()?;
+        walk.simplify_first_parent()?;
+        for oid in walk {
+            let commit = self.repo.find_commit(oid?)?;
+            let message = String::from_utf8_lossy(commit.message_bytes());
+            if pred(&message) {
+                return Ok(Some((commit.id(), message.into_owned())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns contents of the file at `slash_path` as recorded in the HEAD
+    /// commit, or `None` if the path is not present there.
+    #[context("reading {slash_path:?} from HEAD in git repository")]
+    pub fn head_blob(&self, slash_path: &str) -> Result<Option<Vec<u8>>> {
+        let Some(head_tree) = self.head_tree()? else {
+            return Ok(None);
+        };
+        let entry = match head_tree.get_path(Path::new(slash_path)) {
+            Ok(entry) => entry,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let Some(blob) = entry.to_object(&self.repo)?.into_blob().ok() else {
+            return Ok(None);
+        };
+        Ok(Some(blob.content().to_vec()))
+    }
+
+    /// Returns the git blob hash of the file at `slash_path` in the working
+    /// directory, or `None` if the file does not exist.
+    #[context("hashing {slash_path:?} in git working directory")]
+    pub fn workdir_blob_id(&self, slash_path: &str) -> Result<Option<git2::Oid>> {
+        let Some(workdir) = self.repo.workdir() else {
+            bail!("repository has no working directory");
+        };
+        let path = workdir.join(PathBuf::from_slash(slash_path));
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(git2::Oid::hash_file(git2::ObjectType::Blob, path)?))
+    }
+
+    /// Returns a textual patch of changes at `slash_path` pending between the
+    /// index and the working directory. Binary files are only summarized.
+    #[context("diffing {slash_path:?} in git repository")]
+    pub fn pending_diff(&self, slash_path: &str) -> Result<String> {
+        let mut diff_opt = git2::DiffOptions::new();
+        diff_opt
+            .pathspec(slash_path)
+            .disable_pathspec_match(true)
+            .include_untracked(true)
+            .show_untracked_content(true)
+            .recurse_untracked_dirs(true);
+        let diff = self.repo.diff_index_to_workdir(None, Some(&mut diff_opt))?;
+        let mut patch = Vec::new();
+        diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+            if let origin @ ('+' | '-' | ' ') = line.origin() {
+                patch.push(origin as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })?;
+        Ok(String::from_utf8_lossy(&patch).into_owned())
+    }
+
     // TODO: convert to iterator form
     pub fn walk_paths_pre_order<C>(&self, mut callback: C) -> Result<(), git2::Error>
     where
         C: FnMut(String) -> git2::TreeWalkResult,
     {
-        let head = self.repo.head()?;
-        let head_tree = head.peel_to_tree()?;
+        let Some(head_tree) = self.head_tree()? else {
+            return Ok(());
+        };
         head_tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
             if entry.kind() != Some(git2::ObjectType::Blob) {
                 return git2::TreeWalkResult::Ok;
@@ -59,4 +221,26 @@ impl Repo {
             callback(root.to_string() + name)
         })
     }
+
+    /// Returns the tree of the HEAD commit, or `None` if there are no commits
+    /// yet in the repository.
+    fn head_tree(&self) -> Result<Option<git2::Tree<'_>>, git2::Error> {
+        match self.repo.head() {
+            Ok(head) => Ok(Some(head.peel_to_tree()?)),
+            Err(err) if err.code() == git2::ErrorCode::UnbornBranch => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Returns the signature configured in git, or a fallback one identifying
+/// care if none is configured.
+fn signature(repo: &GitRepo) -> Result<git2::Signature<'static>, git2::Error> {
+    match repo.signature() {
+        Ok(sig) => Ok(sig),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => {
+            git2::Signature::now("care", "care@localhost")
+        }
+        Err(err) => Err(err),
+    }
 }
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use itertools::Itertools as _;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::plan::Change;
+
+/// Format of the progress output printed on stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Format {
+    /// Human-readable lines of text.
+    #[default]
+    Text,
+    /// JSON Lines: one JSON object per line, for each event.
+    Json,
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+static PHASE: Mutex<Option<&'static str>> = Mutex::new(None);
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sets the output format; can only be done once, before any output.
+pub fn set_format(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&Format::Json)
+}
+
+/// Prints the progress output on stderr instead, keeping stdout for the
+/// actual result of a command.
+pub fn set_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+fn print_line(line: &str) {
+    match TO_STDERR.load(Ordering::Relaxed) {
+        true => eprintln!("{line}"),
+        false => println!("{line}"),
+    }
+}
+
+/// Details of an action done to a single path. Fields left empty are filled
+/// in automatically by the reporting functions where possible.
+#[derive(Debug, Default, Serialize)]
+pub struct PathEvent<'a> {
+    pub prefix: &'a str,
+    pub subpath: &'a str,
+    pub action: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<Change>,
+    pub result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    PhaseStart {
+        phase: &'a str,
+    },
+    PhaseEnd {
+        phase: &'a str,
+        result: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Step {
+        phase: Option<&'a str>,
+        text: &'a str,
+    },
+    Path {
+        phase: Option<&'a str>,
+        #[serde(flatten)]
+        path: &'a PathEvent<'a>,
+    },
+    Summary {
+        phase: Option<&'a str>,
+        counts: &'a BTreeMap<String, usize>,
+    },
+}
+
+fn emit(event: &Event) {
+    // Serializing plain structs with string keys cannot fail.
+    print_line(&serde_json::to_string(event).unwrap());
+}
+
+fn current_phase() -> Option<&'static str> {
+    *PHASE.lock().unwrap()
+}
+
+/// Runs `f` as a named phase, reporting its start and end, incl. an error
+/// if any. Phases can be nested.
+pub fn phase<T>(name: &'static str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let outer = PHASE.lock().unwrap().replace(name);
+    if is_json() {
+        emit(&Event::PhaseStart { phase: name });
+    }
+    let res = f();
+    if is_json() {
+        emit(&Event::PhaseEnd {
+            phase: name,
+            result: if res.is_ok() { "ok" } else { "error" },
+            error: res.as_ref().err().map(|err| format!("{err:#}")),
+        });
+    }
+    *PHASE.lock().unwrap() = outer;
+    res
+}
+
+/// Reports progress within the current phase.
+pub fn step(text: &str) {
+    if is_json() {
+        emit(&Event::Step {
+            phase: current_phase(),
+            text,
+        });
+    } else {
+        print_line(&format!("care: {text}"));
+    }
+}
+
+/// Prints a line of text, only if the output format is text.
+pub fn text(line: &str) {
+    if !is_json() {
+        print_line(line);
+    }
+}
+
+/// Runs `f` performing an action on a path. In text format, the path is
+/// printed before starting `f`, while in JSON, an event is printed after `f`
+/// finishes, with `result` and `error` set according to the outcome.
+pub fn path<T>(mut event: PathEvent, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    if !is_json() {
+        match event.subpath {
+            "" => print_line(&format!("care:   {}", event.prefix)),
+            subpath => print_line(&format!("care:   {}: {subpath}", event.prefix)),
+        }
+    }
+    let res = f();
+    if is_json() {
+        event.result = if res.is_ok() { "ok" } else { "error" };
+        event.error = res.as_ref().err().map(|err| format!("{err:#}"));
+        path_event(&event);
+    }
+    res
+}
+
+/// Reports an event about a path; only printed if the output format is JSON.
+pub fn path_event(event: &PathEvent) {
+    if is_json() {
+        emit(&Event::Path {
+            phase: current_phase(),
+            path: event,
+        });
+    }
+}
+
+/// Reports numbers of paths per category.
+pub fn summary(counts: &BTreeMap<String, usize>) {
+    if is_json() {
+        emit(&Event::Summary {
+            phase: current_phase(),
+            counts,
+        });
+    } else if counts.is_empty() {
+        print_line("care: Summary: no paths");
+    } else {
+        let summary = counts.iter().map(|(k, n)| format!("{n} {k}")).join(", ");
+        print_line(&format!("care: Summary: {summary}"));
+    }
+}
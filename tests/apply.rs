@@ -0,0 +1,104 @@
+use std::io::Write as _;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// Runs the care binary in `dir` with `args`, feeding `input` on stdin.
+fn care(dir: &Path, args: &[&str], input: &str) -> Output {
+    let mut proc = Command::new(env!("CARGO_BIN_EXE_care"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    proc.stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    proc.wait_with_output().unwrap()
+}
+
+fn ok(output: Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stdout}\n{stderr}");
+    stdout
+}
+
+/// Creates a script managing files `tree` in a `machine` directory, with
+/// an initialized shadow repository, and drafts it.
+fn setup(dir: &Path, tree: &str) {
+    let machine = dir.join("machine");
+    std::fs::create_dir(&machine).unwrap();
+    let script = format!(
+        r#"{{ {:?} = {{ shadow_dir = "shadow", effectors = {{ m = "*lua effectors.posixfiles {}" }}, tree.m = {tree} }} }}"#,
+        script::machine_field().unwrap(),
+        machine.display(),
+    );
+    std::fs::write(dir.join("care.ncl"), script).unwrap();
+    ok(care(dir, &["init"], ""));
+    ok(care(dir, &["draft"], ""));
+}
+
+#[test]
+fn apply_quit_then_resume() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    setup(dir, r#"{ a = "644\nA", b = "644\nB" }"#);
+
+    let out = ok(care(dir, &["apply", "--interactive"], "yes\nquit\n"));
+    assert!(out.contains("Recorded generation 1"), "{out}");
+    assert!(dir.join("machine/a").exists());
+    assert!(!dir.join("machine/b").exists());
+
+    let out = ok(care(dir, &["apply"], ""));
+    assert!(out.contains("Recorded generation 2"), "{out}");
+    assert_eq!(std::fs::read(dir.join("machine/b")).unwrap(), b"B");
+    ok(care(dir, &["check"], ""));
+}
+
+#[test]
+fn apply_failure_then_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    setup(dir, r#"{ a = "644\nA", "sub/c" = "644\nC" }"#);
+
+    // The effector cannot create a file in a missing directory.
+    let out = care(dir, &["apply"], "");
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Recorded generation 1"), "{stdout}");
+    assert!(dir.join("machine/a").exists());
+
+    std::fs::create_dir(dir.join("machine/sub")).unwrap();
+    let out = ok(care(dir, &["apply"], ""));
+    assert!(out.contains("Recorded generation 2"), "{out}");
+    assert_eq!(std::fs::read(dir.join("machine/sub/c")).unwrap(), b"C");
+}
+
+#[test]
+fn check_keeps_unapplied_draft() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    setup(dir, r#"{ a = "644\nA" }"#);
+
+    let out = care(dir, &["check"], "");
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("not applied yet"), "{stderr}");
+    assert!(dir.join("shadow/m/a").exists());
+
+    ok(care(dir, &["check", "--force"], ""));
+    assert!(!dir.join("shadow/m/a").exists());
+
+    // Drift found by a check gets overwritten by the next one.
+    ok(care(dir, &["draft"], ""));
+    ok(care(dir, &["apply"], ""));
+    std::fs::write(dir.join("machine/a"), "changed").unwrap();
+    for _ in 0..2 {
+        let out = care(dir, &["check"], "");
+        assert_eq!(out.status.code(), Some(2));
+    }
+}